@@ -1,5 +1,7 @@
+#[cfg(feature = "server")]
 extern crate askama;
 
 fn main() {
+    #[cfg(feature = "server")]
     askama::rerun_if_templates_changed();
 }
@@ -0,0 +1,575 @@
+use bigdecimal::{BigDecimal, Zero};
+use chrono::Utc;
+use hyper::{Response, Body, HeaderMap, StatusCode};
+use hyper::header::AUTHORIZATION;
+use askama::Template;
+use gotham::handler::HandlerResult;
+use gotham::helpers::http::response::create_response;
+use gotham::state::{FromState, State};
+use serde::Serialize;
+
+use crate::db::{self, Charge, ChargeStatus, CancellationKind, Customer, Payment, RefundStatus, Sale};
+use crate::de::{deserialize_big_decimal, deserialize_opt_big_decimal};
+use crate::middleware::Env;
+use crate::rate_history::RatePoint;
+use crate::ser::serialize_big_decimal;
+use crate::web;
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct AdminChargePath {
+    pub id: u64,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct AdminCustomerPath {
+    pub id: u64,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct AdminRefundPath {
+    pub id: u64,
+}
+
+/// `?format=csv` picks CSV over JSON
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct FormatQuery {
+    format: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "rates_chart.html")]
+struct RatesChartTemplate<'a> {
+    company_name: &'a str,
+}
+
+#[derive(Serialize, Debug)]
+struct ChargeSummary<'a> {
+    id: u64,
+    invoice_id: &'a str,
+    #[serde(serialize_with = "serialize_big_decimal")]
+    eur_amount: &'a BigDecimal,
+    btc_address: &'a str,
+    customer_id: Option<u64>,
+    url: String,
+    status: ChargeStatus,
+    #[serde(serialize_with = "serialize_big_decimal")]
+    paid_eur: BigDecimal,
+    #[serde(serialize_with = "serialize_big_decimal")]
+    amount_due: BigDecimal,
+    /// Transactions acknowledged to a wallet that haven't been recorded as payments yet
+    pending_txids: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct CustomerSummary<'a> {
+    #[serde(flatten)]
+    customer: &'a Customer,
+    url: String,
+    #[serde(serialize_with = "serialize_big_decimal")]
+    outstanding_eur: BigDecimal,
+}
+
+#[derive(Deserialize, Debug)]
+struct NewPayment {
+    txid: String,
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    btc_amount: BigDecimal,
+    /// Defaults to the rate of the last quote shown to the payer
+    #[serde(default, deserialize_with = "deserialize_opt_big_decimal")]
+    btc_rate: Option<BigDecimal>,
+}
+
+/// Check the bearer token of the request, the admin API is disabled
+/// when no token has been configured.
+pub fn is_authorized(state: &State) -> bool {
+    let env = Env::borrow_from(state);
+    let token = match env.admin_token {
+        Some(ref token) => token,
+        None => return false,
+    };
+
+    HeaderMap::borrow_from(state).get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| constant_time_eq(v.as_bytes(), format!("Bearer {}", token).as_bytes()))
+        .unwrap_or(false)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn unauthorized(state: &State) -> Response<Body> {
+    create_response(
+        state,
+        StatusCode::UNAUTHORIZED,
+        mime::TEXT_PLAIN,
+        "Unauthorized",
+    )
+}
+
+fn find_charge(state: &State) -> Option<Charge> {
+    let env = Env::borrow_from(state);
+    let path = AdminChargePath::borrow_from(state);
+    env.db.get_charge_by_id(path.id)
+}
+
+pub fn get_charges(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        charges_response(&state, &env.db.charges())
+    };
+
+    (state, res)
+}
+
+fn charges_response(state: &State, charges: &[Charge]) -> Response<Body> {
+    let env = Env::borrow_from(state);
+    let charges: Vec<_> = charges.iter()
+        .map(|c| ChargeSummary{
+            id: c.id,
+            invoice_id: c.invoice_id.as_str(),
+            eur_amount: &c.eur_amount,
+            btc_address: c.btc_address.as_str(),
+            customer_id: c.customer_id,
+            url: web::pay_url(env, c).into_string(),
+            status: env.db.charge_status(c),
+            paid_eur: env.db.paid_eur(c.id).with_scale(2),
+            amount_due: env.db.amount_due(c).with_scale(2),
+            pending_txids: env.db.pending_payments_for(c.id).into_iter().map(|p| p.txid).collect(),
+        })
+        .collect();
+
+    create_response(
+        state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        serde_json::to_string(&charges).unwrap().into_bytes(),
+    )
+}
+
+pub fn get_customers(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        let customers: Vec<_> = env.db.customers().iter()
+            .map(|c| CustomerSummary{
+                customer: c,
+                url: web::customer_url(env, c).into_string(),
+                outstanding_eur: env.db.charges_for_customer(c.id).iter()
+                    .filter(|charge| env.db.charge_status(charge).is_payable())
+                    .fold(BigDecimal::zero(), |acc, charge| acc + env.db.amount_due(charge))
+                    .with_scale(2),
+            })
+            .collect();
+
+        create_response(
+            &state,
+            StatusCode::OK,
+            mime::APPLICATION_JSON,
+            serde_json::to_string(&customers).unwrap().into_bytes(),
+        )
+    };
+
+    (state, res)
+}
+
+pub fn get_customer_charges(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        let path = AdminCustomerPath::borrow_from(&state);
+        match env.db.get_customer_by_id(path.id) {
+            Some(customer) => charges_response(&state, &env.db.charges_for_customer(customer.id)),
+            None => web::not_found(&state),
+        }
+    };
+
+    (state, res)
+}
+
+pub fn get_ledger(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        create_response(
+            &state,
+            StatusCode::OK,
+            mime::APPLICATION_JSON,
+            serde_json::to_string(&env.db.ledger()).unwrap().into_bytes(),
+        )
+    };
+
+    (state, res)
+}
+
+pub fn get_refunds(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        create_response(
+            &state,
+            StatusCode::OK,
+            mime::APPLICATION_JSON,
+            serde_json::to_string(&env.db.refunds()).unwrap().into_bytes(),
+        )
+    };
+
+    (state, res)
+}
+
+pub fn get_exchanges(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        create_response(
+            &state,
+            StatusCode::OK,
+            mime::APPLICATION_JSON,
+            serde_json::to_string(&env.db.exchanges()).unwrap().into_bytes(),
+        )
+    };
+
+    (state, res)
+}
+
+pub fn get_rates(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        let points = env.rate_history.points();
+        series_response(&state, &points, "at,btc_bid,btc_ask", |p: &RatePoint| {
+            format!("{},{},{}", p.at.to_rfc3339(), p.btc_bid, p.btc_ask)
+        })
+    };
+
+    (state, res)
+}
+
+pub fn get_sales(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        let sales = env.db.sales();
+        series_response(&state, &sales, "sold_at,trade_id,btc_amount,eur_amount,btc_rate", |s: &Sale| {
+            format!("{},{},{},{},{}", s.sold_at.to_rfc3339(), s.trade_id, s.btc_amount, s.eur_amount, s.btc_rate)
+        })
+    };
+
+    (state, res)
+}
+
+fn series_response<T, F>(state: &State, rows: &[T], csv_header: &str, csv_row: F) -> Response<Body>
+    where T: Serialize,
+          F: Fn(&T) -> String,
+{
+    let query = FormatQuery::borrow_from(state);
+    match query.format.as_deref() {
+        None | Some("json") => create_response(
+            state,
+            StatusCode::OK,
+            mime::APPLICATION_JSON,
+            serde_json::to_string(rows).unwrap().into_bytes(),
+        ),
+        Some("csv") => {
+            let mut csv = format!("{}\n", csv_header);
+            for row in rows.iter() {
+                csv.push_str(&csv_row(row));
+                csv.push('\n');
+            }
+            create_response(
+                state,
+                StatusCode::OK,
+                mime::TEXT_CSV,
+                csv.into_bytes(),
+            )
+        },
+        Some(_) => create_response(
+            state,
+            StatusCode::BAD_REQUEST,
+            mime::TEXT_PLAIN,
+            "Unknown format, use json or csv",
+        ),
+    }
+}
+
+/// Chart of the rate history against the quoted and sold rates. The page itself
+/// holds no data, it asks for the admin token and loads everything from the API.
+pub fn get_rates_chart(state: State) -> (State, Response<Body>) {
+    let res = {
+        let env = Env::borrow_from(&state);
+        let html = RatesChartTemplate{
+            company_name: env.company_name.as_deref().unwrap_or("BitCharge"),
+        }.render().unwrap();
+        create_response(
+            &state,
+            StatusCode::OK,
+            mime::TEXT_HTML,
+            html.into_bytes(),
+        )
+    };
+
+    (state, res)
+}
+
+/// Approve a requested refund, or retry one that failed or was left sending, the
+/// worker sends it out on its next run
+pub fn post_approve_refund(state: State) -> (State, Response<Body>) {
+    update_refund(state, &[RefundStatus::Requested, RefundStatus::Failed, RefundStatus::Sending], RefundStatus::Approved)
+}
+
+pub fn post_reject_refund(state: State) -> (State, Response<Body>) {
+    update_refund(state, &[RefundStatus::Requested, RefundStatus::Failed, RefundStatus::Sending], RefundStatus::Rejected)
+}
+
+fn update_refund(state: State, from: &[RefundStatus], to: RefundStatus) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        let path = AdminRefundPath::borrow_from(&state);
+
+        let mut result = Ok(None);
+        for from in from {
+            result = env.db.update_refund(path.id, *from, to);
+            match result {
+                Ok(None) => continue,
+                _ => break,
+            }
+        }
+
+        match result {
+            Ok(Some(refund)) => {
+                info!(refund_id = refund.id, charge_id = refund.charge_id; "Refund {} is now {:?}", refund.id, refund.status);
+                create_response(
+                    &state,
+                    StatusCode::OK,
+                    mime::APPLICATION_JSON,
+                    serde_json::to_string(&refund).unwrap().into_bytes(),
+                )
+            },
+            Ok(None) => create_response(
+                &state,
+                StatusCode::CONFLICT,
+                mime::TEXT_PLAIN,
+                "Refund not found or not in a state that allows this",
+            ),
+            Err(err) => {
+                error!(refund_id = path.id; "Failed to update refund: {:?}", err);
+                create_response(
+                    &state,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    mime::TEXT_PLAIN,
+                    "Failed to update refund",
+                )
+            },
+        }
+    };
+
+    (state, res)
+}
+
+pub async fn post_payment(mut state: State) -> HandlerResult {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return Ok((state, res));
+    }
+
+    match hyper::body::to_bytes(Body::take_from(&mut state)).await {
+        Ok(body) => {
+            let res = record_payment(&state, &body);
+            Ok((state, res))
+        },
+        Err(err) => Err((state, err.into())),
+    }
+}
+
+fn record_payment(state: &State, body: &[u8]) -> Response<Body> {
+    let env = Env::borrow_from(state);
+    let charge = match find_charge(state) {
+        Some(charge) => charge,
+        None => return web::not_found(state),
+    };
+
+    let new_payment = match serde_json::from_slice::<NewPayment>(body) {
+        Ok(p) => p,
+        Err(err) => return create_response(
+            state,
+            StatusCode::BAD_REQUEST,
+            mime::TEXT_PLAIN,
+            format!("Malformed payment: {}", err),
+        ),
+    };
+
+    let btc_rate = new_payment.btc_rate.or_else(|| {
+        let quote = env.caches.quotes().read().unwrap().get(&charge.id).cloned();
+        match quote {
+            Some(quote) => Some(quote.btc_rate),
+            None => env.caches.rates().read().unwrap().try_get().map(|r| r.btc_bid),
+        }
+    });
+    let btc_rate = match btc_rate {
+        Some(btc_rate) => btc_rate,
+        None => return create_response(
+            state,
+            StatusCode::SERVICE_UNAVAILABLE,
+            mime::TEXT_PLAIN,
+            "No current rate to credit the payment at, pass btc_rate",
+        ),
+    };
+    let status = env.db.charge_status(&charge);
+    let payment = Payment{
+        charge_id: charge.id,
+        txid: new_payment.txid,
+        btc_amount: new_payment.btc_amount,
+        btc_rate,
+        received_at: Utc::now(),
+        needs_review: false,
+    };
+
+    info!(charge_id = charge.id, txid = payment.txid.as_str(); "Recording payment {} for {}", payment.txid, charge.invoice_id);
+    match env.db.record_payment(&charge, payment) {
+        Ok(payment) => {
+            if payment.needs_review {
+                warn!(charge_id = charge.id, txid = payment.txid.as_str();
+                      "Late payment {} for {} charge {}, flagged for review",
+                      payment.txid, status.name(), charge.invoice_id);
+            }
+            // Any locked-in quote is for the amount that was due before this payment
+            env.caches.quotes().write().unwrap().remove(&charge.id);
+
+            create_response(
+                state,
+                StatusCode::CREATED,
+                mime::TEXT_PLAIN,
+                "Payment recorded",
+            )
+        },
+        Err(db::Error::DuplicatePayment) => create_response(
+            state,
+            StatusCode::CONFLICT,
+            mime::TEXT_PLAIN,
+            "Payment has already been recorded",
+        ),
+        Err(err) => {
+            error!(charge_id = charge.id; "Failed to record payment: {:?}", err);
+            create_response(
+                state,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                mime::TEXT_PLAIN,
+                "Failed to record payment",
+            )
+        },
+    }
+}
+
+pub fn post_cancel(state: State) -> (State, Response<Body>) {
+    cancel_charge(state, CancellationKind::Cancel)
+}
+
+pub fn post_void(state: State) -> (State, Response<Body>) {
+    cancel_charge(state, CancellationKind::Void)
+}
+
+fn cancel_charge(state: State, kind: CancellationKind) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = match find_charge(&state) {
+        Some(charge) => {
+            let env = Env::borrow_from(&state);
+            info!(charge_id = charge.id; "Marking charge {} as {:?}", charge.invoice_id, kind);
+            match env.db.cancel_charge(charge.id, kind) {
+                Ok(()) => create_response(
+                    &state,
+                    StatusCode::OK,
+                    mime::TEXT_PLAIN,
+                    "Charge cancelled",
+                ),
+                Err(db::Error::HasPayments) => create_response(
+                    &state,
+                    StatusCode::CONFLICT,
+                    mime::TEXT_PLAIN,
+                    "Charge has payments, refund them instead",
+                ),
+                Err(err) => {
+                    error!(charge_id = charge.id; "Failed to cancel charge: {:?}", err);
+                    create_response(
+                        &state,
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        mime::TEXT_PLAIN,
+                        "Failed to cancel charge",
+                    )
+                },
+            }
+        },
+        None => web::not_found(&state),
+    };
+
+    (state, res)
+}
+
+pub fn get_receipt(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = match find_charge(&state) {
+        Some(charge) => web::receipt_response(&state, &charge),
+        None => web::not_found(&state),
+    };
+
+    (state, res)
+}
+
+pub fn get_instructions(state: State) -> (State, Response<Body>) {
+    if !is_authorized(&state) {
+        let res = unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = match find_charge(&state) {
+        Some(charge) => web::instructions_response(&state, &charge),
+        None => web::not_found(&state),
+    };
+
+    (state, res)
+}
@@ -0,0 +1,536 @@
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use bigdecimal::{BigDecimal, Zero};
+use chrono::{DateTime, Duration, Utc};
+use hyper::{self, Method, Request};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+
+use crate::coinmotion::Client;
+use crate::db::ExchangeAction;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AlertsConfig {
+    /// Where alerts are sent, every alert goes to all of them
+    pub channels: Vec<Channel>,
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Channel {
+    /// The alert is POSTed as JSON
+    Webhook {
+        url: String,
+    },
+    /// Slack, Mattermost or another chat incoming webhook that takes `{"text": "..."}`
+    Chat {
+        url: String,
+    },
+    /// Sent with the local sendmail
+    Email {
+        to: String,
+        from: String,
+        #[serde(default = "default_sendmail")]
+        sendmail: String,
+    },
+}
+
+pub fn default_sendmail() -> String {
+    "/usr/sbin/sendmail".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Rule {
+    /// The rates haven't been updated for `minutes`, so no quotes are made
+    RatesStale {
+        minutes: i64,
+    },
+    /// Selling BTC has failed `count` times in a row
+    SellFailures {
+        count: u32,
+    },
+    /// BTC that could be sold has been sitting on Coinmotion for `minutes`, not
+    /// counting an amount below the Coinmotion minimum
+    BtcBalanceStuck {
+        minutes: i64,
+    },
+    /// A withdrawal has been waiting for confirmation for `minutes`, which has to be
+    /// less than the time after which the worker gives up on finding it
+    WithdrawalPending {
+        minutes: i64,
+    },
+}
+
+impl Rule {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Rule::RatesStale{..} => "rates_stale",
+            Rule::SellFailures{..} => "sell_failures",
+            Rule::BtcBalanceStuck{..} => "btc_balance_stuck",
+            Rule::WithdrawalPending{..} => "withdrawal_pending",
+        }
+    }
+
+    fn summary(&self) -> String {
+        match *self {
+            Rule::RatesStale{minutes} => format!("Rates not updated for {} minutes", minutes),
+            Rule::SellFailures{count} => format!("Selling BTC failed {} times in a row", count),
+            Rule::BtcBalanceStuck{minutes} => format!("BTC not sold for {} minutes", minutes),
+            Rule::WithdrawalPending{minutes} => format!("Withdrawal pending for {} minutes", minutes),
+        }
+    }
+
+    /// What is wrong, while the rule fires
+    fn check(&self, observed: &Observed, started_at: DateTime<Utc>, btc_sellable_since: Option<DateTime<Utc>>) -> Option<String> {
+        match *self {
+            Rule::RatesStale{minutes} => {
+                let age = observed.rates_age.unwrap_or_else(|| observed.now - started_at);
+                if age <= Duration::minutes(minutes) {
+                    return None;
+                }
+                Some(match observed.rates_age {
+                    Some(age) => format!("The rates were last updated {} minutes ago", age.num_minutes()),
+                    None => format!("No rates since starting up {} minutes ago", age.num_minutes()),
+                })
+            },
+            Rule::SellFailures{count} => {
+                if observed.sell_failures < count {
+                    return None;
+                }
+                Some(format!("Selling BTC has failed {} times in a row", observed.sell_failures))
+            },
+            Rule::BtcBalanceStuck{minutes} => {
+                let stuck_for = observed.now - btc_sellable_since?;
+                if stuck_for <= Duration::minutes(minutes) {
+                    return None;
+                }
+                Some(format!("{} BTC has been waiting to be sold for {} minutes",
+                             observed.btc_sellable, stuck_for.num_minutes()))
+            },
+            Rule::WithdrawalPending{minutes} => {
+                let withdrawal = observed.pending_withdrawal.as_ref()?;
+                let pending_for = observed.now - withdrawal.submitted_at;
+                if pending_for <= Duration::minutes(minutes) {
+                    return None;
+                }
+                Some(format!("Withdrawal {} of {} EUR has been pending for {} minutes",
+                             withdrawal.id, BigDecimal::new(withdrawal.amount.into(), 2), pending_for.num_minutes()))
+            },
+        }
+    }
+}
+
+/// The state of the worker that the rules are checked against
+#[derive(Debug, Clone)]
+pub struct Observed {
+    pub now: DateTime<Utc>,
+    /// Unknown until the rates have been fetched once
+    pub rates_age: Option<Duration>,
+    pub sell_failures: u32,
+    /// Available BTC that isn't reserved for refunds
+    pub btc_sellable: BigDecimal,
+    /// The last sell was turned down because `btc_sellable` was below the minimum
+    pub sell_below_minimum: bool,
+    pub last_sale: Option<DateTime<Utc>>,
+    pub pending_withdrawal: Option<ExchangeAction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub rule: &'static str,
+    pub status: AlertStatus,
+    pub summary: String,
+    /// What is wrong, left out once resolved
+    pub details: Option<String>,
+    pub at: DateTime<Utc>,
+    #[serde(skip)]
+    rule_index: usize,
+}
+
+impl Alert {
+    fn text(&self) -> String {
+        let status = match self.status {
+            AlertStatus::Firing => "FIRING",
+            AlertStatus::Resolved => "RESOLVED",
+        };
+        match self.details {
+            Some(ref details) => format!("[{}] {}: {}", status, self.summary, details),
+            None => format!("[{}] {}", status, self.summary),
+        }
+    }
+}
+
+/// Keeps track of which rules fire, so that an alert is sent only when a rule
+/// starts firing and when it stops
+pub struct Alerter {
+    rules: Vec<Rule>,
+    firing: Vec<bool>,
+    started_at: DateTime<Utc>,
+    btc_sellable_since: Option<DateTime<Utc>>,
+}
+
+impl Alerter {
+    pub fn new(rules: Vec<Rule>, started_at: DateTime<Utc>) -> Self {
+        Self{
+            firing: vec![false; rules.len()],
+            rules,
+            started_at,
+            btc_sellable_since: None,
+        }
+    }
+
+    /// Alerts for the rules that started or stopped firing since the last check
+    pub fn check(&mut self, observed: &Observed) -> Vec<Alert> {
+        if observed.btc_sellable <= BigDecimal::zero() || observed.sell_below_minimum {
+            self.btc_sellable_since = None;
+        } else {
+            let since = self.btc_sellable_since.unwrap_or(observed.now);
+            // The balance may not have been fetched since the last sale, anything left
+            // over after it has only been waiting since then
+            self.btc_sellable_since = Some(match observed.last_sale {
+                Some(sold_at) if sold_at > since => sold_at,
+                _ => since,
+            });
+        }
+
+        let mut alerts = vec![];
+        for (rule_index, (rule, firing)) in self.rules.iter().zip(self.firing.iter_mut()).enumerate() {
+            let details = rule.check(observed, self.started_at, self.btc_sellable_since);
+            if details.is_some() == *firing {
+                continue;
+            }
+            *firing = details.is_some();
+            alerts.push(Alert{
+                rule: rule.name(),
+                status: if *firing { AlertStatus::Firing } else { AlertStatus::Resolved },
+                summary: rule.summary(),
+                details,
+                at: observed.now,
+                rule_index,
+            });
+        }
+        alerts
+    }
+
+    /// Take back an alert that no channel accepted, so that it is made again on
+    /// the next check
+    pub fn undelivered(&mut self, alert: &Alert) {
+        self.firing[alert.rule_index] = alert.status == AlertStatus::Resolved;
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidUrl(hyper::http::uri::InvalidUri),
+    Connection(hyper::Error),
+    Http(hyper::StatusCode),
+    Sendmail(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidUrl(ref err) => write!(f, "Invalid URL: {}", err),
+            Error::Connection(ref err) => write!(f, "Connection failed: {}", err),
+            Error::Http(status) => write!(f, "Answered with {}", status),
+            Error::Sendmail(ref msg) => write!(f, "sendmail failed: {}", msg),
+        }
+    }
+}
+
+pub async fn deliver(client: &Client, channel: &Channel, alert: &Alert) -> Result<(), Error> {
+    match *channel {
+        Channel::Webhook{ref url} => post_json(client, url, serde_json::to_string(alert).unwrap()).await,
+        Channel::Chat{ref url} => {
+            let body = serde_json::json!({"text": alert.text()});
+            post_json(client, url, body.to_string()).await
+        },
+        Channel::Email{ref to, ref from, ref sendmail} => {
+            let message = format!(
+                "To: {}\r\nFrom: {}\r\nSubject: BitCharge: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}\r\n",
+                to, from, alert.summary, alert.text(),
+            );
+            sendmail_message(sendmail, message).await
+        },
+    }
+}
+
+async fn post_json(client: &Client, url: &str, body: String) -> Result<(), Error> {
+    let mut req = Request::new(body.into());
+    *req.method_mut() = Method::POST;
+    *req.uri_mut() = url.parse().map_err(Error::InvalidUrl)?;
+    req.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(mime::APPLICATION_JSON.as_ref()).unwrap(),
+    );
+
+    let res = client.request(req).await.map_err(Error::Connection)?;
+    if !res.status().is_success() {
+        return Err(Error::Http(res.status()));
+    }
+    Ok(())
+}
+
+/// Hand a complete message, headers included, over to the local sendmail
+pub async fn sendmail_message(sendmail: &str, message: String) -> Result<(), Error> {
+    let sendmail = sendmail.to_string();
+    // sendmail blocks until the message is queued
+    tokio::task::spawn_blocking(move || send_email(&sendmail, &message)).await
+        .map_err(|err| Error::Sendmail(err.to_string()))?
+}
+
+fn send_email(sendmail: &str, message: &str) -> Result<(), Error> {
+    let mut child = Command::new(sendmail)
+        .arg("-t")
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|err| Error::Sendmail(err.to_string()))?;
+    child.stdin.take().unwrap().write_all(message.as_bytes())
+        .map_err(|err| Error::Sendmail(err.to_string()))?;
+    let status = child.wait()
+        .map_err(|err| Error::Sendmail(err.to_string()))?;
+    if !status.success() {
+        return Err(Error::Sendmail(status.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::thread;
+    use chrono::TimeZone;
+    use hyper_tls::HttpsConnector;
+    use crate::db::{ExchangeKind, ExchangeStatus};
+
+    fn observed(minute: i64) -> Observed {
+        Observed{
+            now: Utc.with_ymd_and_hms(2018, 7, 6, 12, 0, 0).unwrap() + Duration::minutes(minute),
+            rates_age: Some(Duration::minutes(1)),
+            sell_failures: 0,
+            btc_sellable: BigDecimal::zero(),
+            sell_below_minimum: false,
+            last_sale: None,
+            pending_withdrawal: None,
+        }
+    }
+
+    fn alerter(rules: Vec<Rule>) -> Alerter {
+        Alerter::new(rules, observed(0).now)
+    }
+
+    #[test]
+    fn alerts_when_a_rule_starts_and_stops_firing() {
+        let mut alerter = alerter(vec![Rule::RatesStale{minutes: 10}]);
+        assert!(alerter.check(&observed(1)).is_empty());
+
+        let stale = Observed{rates_age: Some(Duration::minutes(11)), ..observed(12)};
+        let alerts = alerter.check(&stale);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].rule, "rates_stale");
+        assert_eq!(alerts[0].status, AlertStatus::Firing);
+        assert_eq!(alerts[0].details.as_deref(), Some("The rates were last updated 11 minutes ago"));
+        // Still firing, so nothing new to say
+        assert!(alerter.check(&Observed{rates_age: Some(Duration::minutes(12)), ..observed(13)}).is_empty());
+
+        let alerts = alerter.check(&observed(14));
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].status, AlertStatus::Resolved);
+        assert!(alerts[0].details.is_none());
+    }
+
+    #[test]
+    fn rates_are_stale_when_never_fetched() {
+        let mut alerter = alerter(vec![Rule::RatesStale{minutes: 10}]);
+        assert!(alerter.check(&Observed{rates_age: None, ..observed(5)}).is_empty());
+        let alerts = alerter.check(&Observed{rates_age: None, ..observed(11)});
+        assert_eq!(alerts[0].details.as_deref(), Some("No rates since starting up 11 minutes ago"));
+    }
+
+    #[test]
+    fn sell_failures_in_a_row() {
+        let mut alerter = alerter(vec![Rule::SellFailures{count: 3}]);
+        assert!(alerter.check(&Observed{sell_failures: 2, ..observed(1)}).is_empty());
+        let alerts = alerter.check(&Observed{sell_failures: 3, ..observed(2)});
+        assert_eq!(alerts[0].status, AlertStatus::Firing);
+        assert_eq!(alerter.check(&observed(3))[0].status, AlertStatus::Resolved);
+    }
+
+    #[test]
+    fn btc_balance_stuck_since_it_became_sellable() {
+        let mut alerter = alerter(vec![Rule::BtcBalanceStuck{minutes: 30}]);
+        let btc = BigDecimal::from_str("0.01").unwrap();
+        assert!(alerter.check(&Observed{btc_sellable: btc.clone(), ..observed(10)}).is_empty());
+        assert!(alerter.check(&Observed{btc_sellable: btc.clone(), ..observed(40)}).is_empty());
+
+        let alerts = alerter.check(&Observed{btc_sellable: btc.clone(), ..observed(41)});
+        assert_eq!(alerts[0].details.as_deref(), Some("0.01 BTC has been waiting to be sold for 31 minutes"));
+
+        // Sold, and some more BTC came in, which starts the wait over
+        assert_eq!(alerter.check(&observed(45))[0].status, AlertStatus::Resolved);
+        assert!(alerter.check(&Observed{btc_sellable: btc, ..observed(70)}).is_empty());
+    }
+
+    #[test]
+    fn btc_balance_stuck_since_the_last_sale() {
+        let mut alerter = alerter(vec![Rule::BtcBalanceStuck{minutes: 30}]);
+        let btc = BigDecimal::from_str("0.01").unwrap();
+        assert!(alerter.check(&Observed{btc_sellable: btc.clone(), ..observed(10)}).is_empty());
+
+        // More BTC came in before the balance was fetched again after the sale
+        let sold_at = observed(35).now;
+        assert!(alerter.check(&Observed{btc_sellable: btc.clone(), last_sale: Some(sold_at), ..observed(41)}).is_empty());
+        assert!(alerter.check(&Observed{btc_sellable: btc.clone(), last_sale: Some(sold_at), ..observed(65)}).is_empty());
+        let alerts = alerter.check(&Observed{btc_sellable: btc, last_sale: Some(sold_at), ..observed(66)});
+        assert_eq!(alerts[0].details.as_deref(), Some("0.01 BTC has been waiting to be sold for 31 minutes"));
+    }
+
+    #[test]
+    fn btc_below_the_minimum_isnt_stuck() {
+        let mut alerter = alerter(vec![Rule::BtcBalanceStuck{minutes: 30}]);
+        let dust = Observed{btc_sellable: BigDecimal::from_str("0.00001").unwrap(), sell_below_minimum: true, ..observed(0)};
+        assert!(alerter.check(&Observed{now: observed(10).now, ..dust.clone()}).is_empty());
+        assert!(alerter.check(&Observed{now: observed(60).now, ..dust}).is_empty());
+    }
+
+    #[test]
+    fn undelivered_alerts_are_made_again() {
+        let mut alerter = alerter(vec![Rule::SellFailures{count: 3}]);
+        let failing = Observed{sell_failures: 3, ..observed(1)};
+        let alerts = alerter.check(&failing);
+        alerter.undelivered(&alerts[0]);
+        let alerts = alerter.check(&failing);
+        assert_eq!(alerts[0].status, AlertStatus::Firing);
+        assert!(alerter.check(&failing).is_empty());
+
+        let alerts = alerter.check(&observed(2));
+        alerter.undelivered(&alerts[0]);
+        assert_eq!(alerter.check(&observed(3))[0].status, AlertStatus::Resolved);
+    }
+
+    #[test]
+    fn withdrawal_pending_too_long() {
+        let mut alerter = alerter(vec![Rule::WithdrawalPending{minutes: 60}]);
+        let withdrawal = ExchangeAction{
+            id: 7,
+            kind: ExchangeKind::Withdrawal,
+            amount: 12345,
+            balance_before: BigDecimal::from_str("124.35").unwrap(),
+            status: ExchangeStatus::Submitted,
+            submitted_at: observed(0).now,
+            reference: None,
+            failure: None,
+        };
+        assert!(alerter.check(&Observed{pending_withdrawal: Some(withdrawal.clone()), ..observed(60)}).is_empty());
+        let alerts = alerter.check(&Observed{pending_withdrawal: Some(withdrawal), ..observed(61)});
+        assert_eq!(alerts[0].details.as_deref(), Some("Withdrawal 7 of 123.45 EUR has been pending for 61 minutes"));
+    }
+
+    #[test]
+    fn parse_config() {
+        #[derive(Deserialize)]
+        struct Conf {
+            alerts: AlertsConfig,
+        }
+        let conf: Conf = toml::from_str(r#"
+            [[alerts.channels]]
+            kind = "chat"
+            url = "https://hooks.slack.com/services/X"
+
+            [[alerts.channels]]
+            kind = "email"
+            to = "ops@example.com"
+            from = "bitcharge@example.com"
+
+            [[alerts.rules]]
+            kind = "rates_stale"
+            minutes = 10
+
+            [[alerts.rules]]
+            kind = "sell_failures"
+            count = 3
+        "#).unwrap();
+
+        assert_eq!(conf.alerts.rules, vec![Rule::RatesStale{minutes: 10}, Rule::SellFailures{count: 3}]);
+        match conf.alerts.channels[1] {
+            Channel::Email{ref sendmail, ..} => assert_eq!(sendmail, "/usr/sbin/sendmail"),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Answer a single request with `status` and hand back its body
+    fn http_sink(status: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(len) = line.strip_prefix("content-length:") {
+                    content_length = len.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+            String::from_utf8(body).unwrap()
+        });
+        (url, handle)
+    }
+
+    fn alert() -> Alert {
+        Alert{
+            rule: "sell_failures",
+            status: AlertStatus::Firing,
+            summary: Rule::SellFailures{count: 3}.summary(),
+            details: Some("Selling BTC has failed 3 times in a row".to_string()),
+            at: observed(0).now,
+            rule_index: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn deliver_to_webhook_and_chat() {
+        let client: Client = hyper::Client::builder()
+            .build::<_, hyper::Body>(HttpsConnector::new());
+
+        let (url, sink) = http_sink("200 OK");
+        deliver(&client, &Channel::Webhook{url}, &alert()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(&sink.join().unwrap()).unwrap();
+        assert_eq!(body["rule"], "sell_failures");
+        assert_eq!(body["status"], "firing");
+        assert_eq!(body["details"], "Selling BTC has failed 3 times in a row");
+
+        let (url, sink) = http_sink("200 OK");
+        deliver(&client, &Channel::Chat{url}, &alert()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_str(&sink.join().unwrap()).unwrap();
+        assert_eq!(body["text"], "[FIRING] Selling BTC failed 3 times in a row: Selling BTC has failed 3 times in a row");
+
+        let (url, sink) = http_sink("500 Internal Server Error");
+        match deliver(&client, &Channel::Webhook{url}, &alert()).await {
+            Err(Error::Http(status)) => assert_eq!(status.as_u16(), 500),
+            res => panic!("unexpected {:?}", res),
+        }
+        sink.join().unwrap();
+    }
+}
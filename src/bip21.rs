@@ -0,0 +1,129 @@
+use std::fmt;
+use bigdecimal::BigDecimal;
+
+/// Builder for BIP21 `bitcoin:` payment URIs.
+///
+/// All the optional parameters are percent-encoded, so that arbitrary
+/// labels and messages can be passed in safely.
+#[derive(Debug, Clone)]
+pub struct Bip21Uri {
+    address: String,
+    amount: Option<BigDecimal>,
+    label: Option<String>,
+    message: Option<String>,
+    lightning: Option<String>,
+}
+
+impl Bip21Uri {
+    pub fn new(address: &str) -> Self {
+        Self{
+            address: address.to_string(),
+            amount: None,
+            label: None,
+            message: None,
+            lightning: None,
+        }
+    }
+
+    /// Amount in BTC to request.
+    pub fn amount(mut self, amount: BigDecimal) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+
+    /// Label for the recipient, shown by wallets as the payee.
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    /// Message describing the payment, shown by wallets to the payer.
+    pub fn message(mut self, message: &str) -> Self {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    /// Lightning invoice to offer as an alternative payment method.
+    pub fn lightning(mut self, invoice: &str) -> Self {
+        self.lightning = Some(invoice.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Bip21Uri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bitcoin:{}", self.address)?;
+
+        let mut sep = '?';
+        if let Some(ref amount) = self.amount {
+            write!(f, "{}amount={}", sep, amount)?;
+            sep = '&';
+        }
+
+        let params = [
+            ("label", &self.label),
+            ("message", &self.message),
+            ("lightning", &self.lightning),
+        ];
+        for &(key, value) in params.iter() {
+            if let Some(ref value) = *value {
+                write!(f, "{}{}={}", sep, key, percent_encode(value))?;
+                sep = '&';
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Percent-encode everything but the RFC 3986 unreserved characters.
+fn percent_encode(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char);
+            },
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn address_only() {
+        let uri = Bip21Uri::new("1Archive1n2C579dMsAu3iC6tWzuQJz8dN");
+        assert_eq!(uri.to_string(), "bitcoin:1Archive1n2C579dMsAu3iC6tWzuQJz8dN");
+    }
+
+    #[test]
+    fn all_parameters() {
+        let uri = Bip21Uri::new("1Archive1n2C579dMsAu3iC6tWzuQJz8dN")
+            .amount(BigDecimal::from_str("0.0123").unwrap())
+            .label("Acme & Sons OÜ")
+            .message("Invoice 2018-0012")
+            .lightning("lnbc1");
+        assert_eq!(
+            uri.to_string(),
+            "bitcoin:1Archive1n2C579dMsAu3iC6tWzuQJz8dN?amount=0.0123\
+             &label=Acme%20%26%20Sons%20O%C3%9C\
+             &message=Invoice%202018-0012\
+             &lightning=lnbc1",
+        );
+    }
+
+    #[test]
+    fn message_without_amount() {
+        let uri = Bip21Uri::new("1Archive1n2C579dMsAu3iC6tWzuQJz8dN")
+            .message("a=b");
+        assert_eq!(
+            uri.to_string(),
+            "bitcoin:1Archive1n2C579dMsAu3iC6tWzuQJz8dN?message=a%3Db",
+        );
+    }
+}
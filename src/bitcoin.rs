@@ -0,0 +1,410 @@
+use sha2::{Sha256, Digest};
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc8_30a3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Network {
+    Bitcoin,
+    Testnet,
+}
+
+impl Network {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Network::Bitcoin => "bitcoin",
+            Network::Testnet => "test",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Address,
+    Checksum,
+    Hex,
+    Transaction,
+}
+
+/// A decoded bitcoin address
+#[derive(Debug, Clone, PartialEq)]
+pub struct Address {
+    pub network: Network,
+    /// The scriptPubKey that pays to this address
+    pub script: Vec<u8>,
+}
+
+impl Address {
+    pub fn parse(addr: &str) -> Result<Self, Error> {
+        let lower = addr.to_lowercase();
+        if lower.starts_with("bc1") || lower.starts_with("tb1") {
+            decode_segwit(addr)
+        } else {
+            decode_base58(addr)
+        }
+    }
+}
+
+fn decode_base58(addr: &str) -> Result<Address, Error> {
+    let mut bytes: Vec<u8> = vec![];
+    for c in addr.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|&a| a == c)
+            .ok_or(Error::Address)? as u32;
+        for b in bytes.iter_mut().rev() {
+            carry += (*b as u32) * 58;
+            *b = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.insert(0, carry as u8);
+            carry >>= 8;
+        }
+    }
+    // Leading '1' characters stand for leading zero bytes
+    let zeros = addr.bytes().take_while(|&c| c == b'1').count();
+    let mut data = vec![0u8; zeros];
+    data.extend(bytes);
+
+    if data.len() != 25 {
+        return Err(Error::Address);
+    }
+    let checksum = double_sha256(&data[..21]);
+    if checksum[..4] != data[21..] {
+        return Err(Error::Checksum);
+    }
+
+    let hash = &data[1..21];
+    let (network, script) = match data[0] {
+        0x00 => (Network::Bitcoin, p2pkh_script(hash)),
+        0x05 => (Network::Bitcoin, p2sh_script(hash)),
+        0x6f => (Network::Testnet, p2pkh_script(hash)),
+        0xc4 => (Network::Testnet, p2sh_script(hash)),
+        _ => return Err(Error::Address),
+    };
+    Ok(Address{ network, script })
+}
+
+fn p2pkh_script(hash: &[u8]) -> Vec<u8> {
+    // OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(hash);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+fn p2sh_script(hash: &[u8]) -> Vec<u8> {
+    // OP_HASH160 <hash> OP_EQUAL
+    let mut script = vec![0xa9, 0x14];
+    script.extend_from_slice(hash);
+    script.push(0x87);
+    script
+}
+
+fn decode_segwit(addr: &str) -> Result<Address, Error> {
+    // Mixed case is not allowed
+    if addr.to_lowercase() != addr && addr.to_uppercase() != addr {
+        return Err(Error::Address);
+    }
+    let addr = addr.to_lowercase();
+    let sep = addr.rfind('1').ok_or(Error::Address)?;
+    let (hrp, data) = (&addr[..sep], &addr[sep + 1..]);
+    let network = match hrp {
+        "bc" => Network::Bitcoin,
+        "tb" => Network::Testnet,
+        _ => return Err(Error::Address),
+    };
+
+    let mut values = vec![];
+    for c in data.bytes() {
+        let v = BECH32_CHARSET.iter().position(|&a| a == c)
+            .ok_or(Error::Address)?;
+        values.push(v as u8);
+    }
+    if values.len() < 7 {
+        return Err(Error::Address);
+    }
+
+    let mut check = bech32_hrp_expand(hrp);
+    check.extend_from_slice(&values);
+    let version = values[0];
+    let expected = if version == 0 { BECH32_CONST } else { BECH32M_CONST };
+    if bech32_polymod(&check) != expected {
+        return Err(Error::Checksum);
+    }
+
+    let program = convert_bits(&values[1..values.len() - 6], 5, 8)
+        .ok_or(Error::Address)?;
+    if version > 16 || program.len() < 2 || program.len() > 40 {
+        return Err(Error::Address);
+    }
+    if version == 0 && program.len() != 20 && program.len() != 32 {
+        return Err(Error::Address);
+    }
+
+    // OP_n <program>
+    let mut script = vec![if version == 0 { 0x00 } else { 0x50 + version }];
+    script.push(program.len() as u8);
+    script.extend(program);
+    Ok(Address{ network, script })
+}
+
+fn bech32_hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut v: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    v.push(0);
+    v.extend(hrp.bytes().map(|b| b & 31));
+    v
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GEN: [u32; 5] = [0x3b6a_57b2, 0x2650_8e6d, 0x1ea1_19fa, 0x3d42_33dd, 0x2a14_62b3];
+    let mut chk: u32 = 1;
+    for &v in values {
+        let b = chk >> 25;
+        chk = (chk & 0x1ff_ffff) << 5 ^ (v as u32);
+        for (i, g) in GEN.iter().enumerate() {
+            if (b >> i) & 1 == 1 {
+                chk ^= *g;
+            }
+        }
+    }
+    chk
+}
+
+fn convert_bits(data: &[u8], from: u32, to: u32) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let mut out = vec![];
+    let maxv = (1 << to) - 1;
+    for &v in data {
+        acc = (acc << from) | (v as u32);
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            out.push(((acc >> bits) & maxv) as u8);
+        }
+    }
+    // Only zero padding of less than a full group is allowed
+    if bits >= from || ((acc << (to - bits)) & maxv) != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+/// The parts of a raw transaction we care about
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub txid: String,
+    pub outputs: Vec<TxOut>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TxOut {
+    pub satoshis: u64,
+    pub script: Vec<u8>,
+}
+
+impl Transaction {
+    pub fn parse(raw: &[u8]) -> Result<Self, Error> {
+        let mut r = Reader{ data: raw, pos: 0 };
+        r.skip(4)?;
+
+        // Segwit transactions have a zero marker where the input count would be
+        let segwit = r.peek()? == 0;
+        if segwit {
+            r.skip(2)?;
+        }
+        let body_start = r.pos;
+
+        let num_inputs = r.varint()?;
+        for _ in 0..num_inputs {
+            r.skip(32 + 4)?;
+            let len = r.varint()? as usize;
+            r.skip(len.checked_add(4).ok_or(Error::Transaction)?)?;
+        }
+
+        let num_outputs = r.varint()?;
+        let mut outputs = vec![];
+        for _ in 0..num_outputs {
+            let satoshis = r.u64_le()?;
+            let len = r.varint()? as usize;
+            let script = r.take(len)?.to_vec();
+            outputs.push(TxOut{ satoshis, script });
+        }
+        let body_end = r.pos;
+
+        if segwit {
+            for _ in 0..num_inputs {
+                let num_items = r.varint()?;
+                for _ in 0..num_items {
+                    let len = r.varint()? as usize;
+                    r.skip(len)?;
+                }
+            }
+        }
+        r.skip(4)?;
+        if r.pos != raw.len() {
+            return Err(Error::Transaction);
+        }
+
+        // The txid never covers the witness data
+        let mut stripped = raw[..4].to_vec();
+        stripped.extend_from_slice(&raw[body_start..body_end]);
+        stripped.extend_from_slice(&raw[raw.len() - 4..]);
+        let mut hash = double_sha256(&stripped);
+        hash.reverse();
+
+        Ok(Transaction{
+            txid: to_hex(&hash),
+            outputs,
+        })
+    }
+
+    /// Total amount paid to the given script
+    pub fn satoshis_to(&self, script: &[u8]) -> u64 {
+        self.outputs.iter()
+            .filter(|o| o.script.as_slice() == script)
+            .map(|o| o.satoshis)
+            .sum()
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let end = self.pos.checked_add(n)
+            .filter(|&end| end <= self.data.len())
+            .ok_or(Error::Transaction)?;
+        let data = self.data;
+        let bytes = &data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), Error> {
+        self.take(n).map(|_| ())
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.data.get(self.pos).cloned().ok_or(Error::Transaction)
+    }
+
+    fn u64_le(&mut self) -> Result<u64, Error> {
+        let bytes = self.take(8)?;
+        Ok(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let n = match self.take(1)?[0] {
+            0xfd => 2,
+            0xfe => 4,
+            0xff => 8,
+            v => return Ok(v as u64),
+        };
+        let bytes = self.take(n)?;
+        Ok(bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+    }
+}
+
+pub fn double_sha256(data: &[u8]) -> Vec<u8> {
+    let mut first = Sha256::default();
+    first.input(data);
+    let mut second = Sha256::default();
+    second.input(first.result().as_slice());
+    second.result().to_vec()
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Result<Vec<u8>, Error> {
+    if !s.len().is_multiple_of(2) {
+        return Err(Error::Hex);
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i + 2)
+            .and_then(|b| u8::from_str_radix(b, 16).ok())
+            .ok_or(Error::Hex))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_p2pkh_address() {
+        let addr = Address::parse("1Archive1n2C579dMsAu3iC6tWzuQJz8dN").unwrap();
+        assert_eq!(addr.network, Network::Bitcoin);
+        assert_eq!(
+            to_hex(&addr.script),
+            "76a9146c1b511a63306b6294e1d3390ac8cd481f76705d88ac",
+        );
+    }
+
+    #[test]
+    fn parse_p2sh_address() {
+        let addr = Address::parse("3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy").unwrap();
+        assert_eq!(
+            to_hex(&addr.script),
+            "a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87",
+        );
+    }
+
+    #[test]
+    fn parse_segwit_addresses() {
+        let addr = Address::parse("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4").unwrap();
+        assert_eq!(
+            to_hex(&addr.script),
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+        );
+        let addr = Address::parse(
+            "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0").unwrap();
+        assert_eq!(
+            to_hex(&addr.script),
+            "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        );
+    }
+
+    #[test]
+    fn reject_invalid_addresses() {
+        assert_eq!(
+            Address::parse("1Archive1n2C579dMsAu3iC6tWzuQJz8dM"),
+            Err(Error::Checksum),
+        );
+        assert_eq!(
+            Address::parse("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5"),
+            Err(Error::Checksum),
+        );
+        assert_eq!(Address::parse("0OIl"), Err(Error::Address));
+    }
+
+    #[test]
+    fn parse_transaction() {
+        // Spends the genesis-era coinbase output of block 9 to Hal Finney
+        let raw = from_hex(
+            "0100000001c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f51b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a0626f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac00000000",
+        ).unwrap();
+        let tx = Transaction::parse(&raw).unwrap();
+        assert_eq!(tx.txid, "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16");
+        assert_eq!(tx.outputs.len(), 2);
+        assert_eq!(tx.outputs[0].satoshis, 10_0000_0000);
+        assert_eq!(tx.outputs[1].satoshis, 40_0000_0000);
+    }
+
+    #[test]
+    fn reject_huge_script_length() {
+        // One input whose script length varint is u64::MAX
+        let mut raw = from_hex("0100000001").unwrap();
+        raw.extend_from_slice(&[0; 36]);
+        raw.extend_from_slice(&[0xff; 9]);
+        raw.extend_from_slice(&[0; 8]);
+        assert_eq!(Transaction::parse(&raw).map(|_| ()), Err(Error::Transaction));
+    }
+}
@@ -1,12 +1,28 @@
-use coinmotion;
+use crate::coinmotion::{self, CallStats};
+use crate::quote::Quote;
+use crate::rates::Disagreement;
+use crate::retry::CircuitBreaker;
+use crate::worker::JobStats;
 
-use std::sync::RwLock;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
 use std::time::{Duration, SystemTime};
 
 type RatesCache = ExpiringValueCache<coinmotion::Rates>;
 
 pub struct Caches {
     rates: RwLock<RatesCache>,
+    quotes: RwLock<HashMap<u64, Quote>>,
+    rates_trusted: RwLock<bool>,
+    rate_disagreement: RwLock<Option<Disagreement>>,
+    coinmotion_circuit: Arc<CircuitBreaker>,
+    jobs: RwLock<BTreeMap<&'static str, JobStats>>,
+    worker_heartbeat: RwLock<Option<SystemTime>>,
+    coinmotion_calls: Arc<CallStats>,
+    balances: RwLock<Option<coinmotion::Balances>>,
+    sell_failures: RwLock<u32>,
+    sell_below_minimum: RwLock<bool>,
+    pay_page_hits: RwLock<BTreeMap<u64, u64>>,
 }
 
 impl Caches {
@@ -15,12 +31,79 @@ impl Caches {
             rates: RwLock::new(RatesCache::new(
                 Duration::from_secs(3600),
             )),
+            quotes: RwLock::new(HashMap::new()),
+            rates_trusted: RwLock::new(false),
+            rate_disagreement: RwLock::new(None),
+            coinmotion_circuit: Arc::new(CircuitBreaker::new(
+                coinmotion::CIRCUIT_FAILURE_THRESHOLD,
+                Duration::from_secs(coinmotion::CIRCUIT_COOLDOWN_SECS),
+            )),
+            jobs: RwLock::new(BTreeMap::new()),
+            worker_heartbeat: RwLock::new(None),
+            coinmotion_calls: Arc::new(CallStats::new()),
+            balances: RwLock::new(None),
+            sell_failures: RwLock::new(0),
+            sell_below_minimum: RwLock::new(false),
+            pay_page_hits: RwLock::new(BTreeMap::new()),
         }
     }
 
     pub fn rates(&self) -> &RwLock<RatesCache> {
         &self.rates
     }
+
+    /// Quotes locked in for charges, keyed by charge ID
+    pub fn quotes(&self) -> &RwLock<HashMap<u64, Quote>> {
+        &self.quotes
+    }
+
+    /// Cleared when the rate sources disagree, no new quotes are made until they agree again
+    pub fn rates_trusted(&self) -> &RwLock<bool> {
+        &self.rates_trusted
+    }
+
+    /// The last time the rate sources disagreed, which source came closest and how far off it was
+    pub fn rate_disagreement(&self) -> &RwLock<Option<Disagreement>> {
+        &self.rate_disagreement
+    }
+
+    pub fn coinmotion_circuit(&self) -> &Arc<CircuitBreaker> {
+        &self.coinmotion_circuit
+    }
+
+    /// Runs of the worker jobs, keyed by job name
+    pub fn jobs(&self) -> &RwLock<BTreeMap<&'static str, JobStats>> {
+        &self.jobs
+    }
+
+    /// Last time the worker's scheduler looked for jobs to run
+    pub fn worker_heartbeat(&self) -> &RwLock<Option<SystemTime>> {
+        &self.worker_heartbeat
+    }
+
+    pub fn coinmotion_calls(&self) -> &Arc<CallStats> {
+        &self.coinmotion_calls
+    }
+
+    /// Coinmotion balances as last fetched by the worker
+    pub fn balances(&self) -> &RwLock<Option<coinmotion::Balances>> {
+        &self.balances
+    }
+
+    /// Sells that failed in a row, cleared by the next one that goes through
+    pub fn sell_failures(&self) -> &RwLock<u32> {
+        &self.sell_failures
+    }
+
+    /// Whether Coinmotion turned the last sell down as below its minimum amount
+    pub fn sell_below_minimum(&self) -> &RwLock<bool> {
+        &self.sell_below_minimum
+    }
+
+    /// Views of the pay page, keyed by charge ID
+    pub fn pay_page_hits(&self) -> &RwLock<BTreeMap<u64, u64>> {
+        &self.pay_page_hits
+    }
 }
 
 pub struct ExpiringValueCache<T> {
@@ -40,18 +123,18 @@ impl<T> ExpiringValueCache<T>
         }
     }
 
-    pub fn get(&self) -> T {
+    /// The value, unless it hasn't been cached yet or has gone stale
+    pub fn try_get(&self) -> Option<T> {
         match self.update_time.elapsed() {
-            Ok(elapsed) => {
-                if elapsed > self.valid_for {
-                    panic!("Cached value too stale");
-                }
-            },
-            Err(err) => warn!("Cache update timestamp time-travelled - {:?}", err),
+            Ok(elapsed) if elapsed > self.valid_for => None,
+            _ => self.value.clone(),
         }
+    }
 
-        let value = self.value.clone();
-        value.expect("Value not cached yet")
+    /// Time since the value was cached, if it ever was
+    pub fn age(&self) -> Option<Duration> {
+        self.value.as_ref()
+            .map(|_| self.update_time.elapsed().unwrap_or_default())
     }
 
     pub fn set(&mut self, value: T) {
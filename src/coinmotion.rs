@@ -1,141 +1,339 @@
-use std::fmt::Display;
-use std::time::{SystemTime, UNIX_EPOCH};
+//! Client for the Coinmotion v1 API. Signed requests get a nonce from a shared
+//! [`Nonces`](../nonce/struct.Nonces.html), calls that only read data are retried and
+//! a [`CircuitBreaker`](../retry/struct.CircuitBreaker.html) stops calls while Coinmotion is failing.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use bigdecimal::BigDecimal;
 use serde::de::{self, Deserialize, DeserializeOwned, Deserializer};
 use serde::{Serialize, Serializer};
-use serde_json;
-use hyper::{self, Method, Request};
-use hyper::header::{HeaderValue, CONTENT_TYPE};
-use futures::{Future, Stream};
+use hyper::{self, Method, Request, StatusCode};
+use hyper::header::{HeaderValue, CONTENT_TYPE, RETRY_AFTER};
 use hyper_tls::HttpsConnector;
 use sha2::Sha512;
 use hmac::{Hmac, Mac};
-use mime;
 
-use de::deserialize_big_decimal;
+use crate::de::{deserialize_big_decimal, deserialize_id};
+use crate::nonce::{self, Nonces};
+use crate::retry::{self, CircuitBreaker};
 
-type Client = hyper::Client<HttpsConnector<hyper::client::HttpConnector>, hyper::Body>;
+pub type Client = hyper::Client<HttpsConnector<hyper::client::HttpConnector>, hyper::Body>;
 
 /// Coinmotion withdrawal fee in EUR
 pub const WITHDRAWAL_FEE: &str = "0.90";
 
+/// Attempts made for calls that are safe to repeat
+const READ_ATTEMPTS: u32 = 4;
+/// Calls that move money are never repeated, a call that timed out may still have gone through
+const WRITE_ATTEMPTS: u32 = 1;
+/// Longest wait before a retry, e.g. when rate limited
+const MAX_RETRY_DELAY_SECS: u64 = 30;
+/// Consecutive failed calls before calls are stopped for a while
+pub const CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+pub const CIRCUIT_COOLDOWN_SECS: u64 = 60;
+
+/// ```no_run
+/// use std::sync::Arc;
+/// use std::time::Duration;
+/// use hyper_tls::HttpsConnector;
+/// use bitcharge::Coinmotion;
+/// use bitcharge::coinmotion::CallStats;
+/// use bitcharge::nonce::Nonces;
+/// use bitcharge::retry::CircuitBreaker;
+///
+/// # async fn run() {
+/// let client = hyper::Client::builder()
+///     .build::<_, hyper::Body>(HttpsConnector::new());
+/// let nonces = Arc::new(Nonces::open("bitcharge-nonce").unwrap());
+/// let stats = Arc::new(CallStats::new());
+/// let api = Coinmotion::new(&client, "API-KEY", "API-SECRET", nonces)
+///     .circuit(Arc::new(CircuitBreaker::new(3, Duration::from_secs(120))))
+///     .stats(stats);
+///
+/// let balances = api.balances().await.unwrap();
+/// println!("{} BTC available", balances.btc_avl);
+/// # }
+/// ```
 pub struct Coinmotion<'a> {
     base_url: &'a str,
     api_key: &'a str,
     api_secret: &'a str,
     client: &'a Client,
+    circuit: Arc<CircuitBreaker>,
+    nonces: Arc<Nonces>,
+    stats: Arc<CallStats>,
 }
 
 impl<'a> Coinmotion<'a> {
-    pub fn new(client: &'a Client, api_key: &'a str, api_secret: &'a str) -> Self {
+    /// Every signed call made with the same API key must share `nonces`
+    pub fn new(client: &'a Client, api_key: &'a str, api_secret: &'a str, nonces: Arc<Nonces>) -> Self {
         Self{
             base_url: "https://api.coinmotion.com/v1",
             api_key,
             api_secret,
             client,
+            circuit: Arc::new(CircuitBreaker::new(
+                CIRCUIT_FAILURE_THRESHOLD,
+                Duration::from_secs(CIRCUIT_COOLDOWN_SECS),
+            )),
+            nonces,
+            stats: Arc::new(CallStats::new()),
         }
     }
 
-    fn request<R>(&self, endpoint: &'static str, req: Request<hyper::Body>) -> impl Future<Item=R, Error=Error>
-        where R: DeserializeOwned
+    /// Circuit breaker to use in place of the default one, e.g. to share it with
+    /// whoever reports on the health of the connection
+    pub fn circuit(mut self, circuit: Arc<CircuitBreaker>) -> Self {
+        self.circuit = circuit;
+        self
+    }
+
+    /// Where to count the calls made, e.g. to report them as metrics
+    pub fn stats(mut self, stats: Arc<CallStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Make the request, retrying transient failures with backoff. `build` is called
+    /// for every attempt, so that signed requests get a fresh nonce.
+    async fn request<R, F>(&self, endpoint: &'static str, attempts: u32, build: F) -> Result<R, Error>
+        where R: DeserializeOwned,
+              F: Fn() -> Result<Request<hyper::Body>, Error>
     {
-        self.client.request(req)
-            .map_err(Error::ConnectionError)
-            .and_then(move |res| {
-                trace!("Coinmotion API [{}] response {}", endpoint, res.status());
-
-                res.into_body().concat2()
-                    .map_err(Error::ConnectionError)
-                    .and_then(|body| {
-                        serde_json::from_slice::<ResponseWrapper<R>>(&body)
-                            .map_err(Error::ParseError)
-                })
-            })
-            .and_then(|resp| {
-                match resp {
-                ResponseWrapper::Response(r) => Ok(r),
-                ResponseWrapper::Error(err) => Err(Error::BackendError(err)),
-                ResponseWrapper::UnknownStatus(s) => Err(Error::UnknownStatus(s)),
-                }
-            })
-    }
-
-    fn post<R, P>(&self, endpoint: &'static str, request: P) -> impl Future<Item=R, Error=Error>
+        let mut attempt = 0;
+        let mut nonce_recovered = false;
+
+        loop {
+            if !self.circuit.allow() {
+                self.stats.record_error(endpoint, &Error::CircuitOpen);
+                return Err(Error::CircuitOpen);
+            }
+            let err = match send(self.client, endpoint, build()?).await {
+                Ok(r) => {
+                    self.circuit.record_success();
+                    self.stats.record_success(endpoint);
+                    return Ok(r);
+                },
+                Err(err) => err,
+            };
+            self.stats.record_error(endpoint, &err);
+            if err.is_nonce_error() && !nonce_recovered {
+                // The request was turned down without being carried out,
+                // so it is safe to repeat with a nonce that is further ahead
+                self.circuit.record_success();
+                self.nonces.recover().map_err(Error::NonceError)?;
+                nonce_recovered = true;
+                continue;
+            }
+            if !err.is_transient() {
+                // Coinmotion answered, so it is up
+                self.circuit.record_success();
+                return Err(err);
+            }
+
+            self.circuit.record_failure();
+            let delay = cmp::max(err.retry_after().unwrap_or_default(), retry::backoff(attempt));
+            // Anything longer is left for the next run of the task
+            if attempt + 1 >= attempts || delay > Duration::from_secs(MAX_RETRY_DELAY_SECS) {
+                return Err(err);
+            }
+            warn!(endpoint, error = err.name(); "Coinmotion API [{}] failed, retrying in {:?}: {}", endpoint, delay, err);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    async fn post<R, P>(&self, endpoint: &'static str, attempts: u32, request: P) -> Result<R, Error>
         where P: Serialize,
               R: DeserializeOwned
     {
         let url = format!("{}{}", self.base_url, endpoint);
-        let request = RequestWrapper{
-            nonce: next_nonce(),
-            request,
-        };
-        let request = serde_json::to_string(&request).unwrap();
-
-        let mut mac = Hmac::<Sha512>::new_varkey(self.api_secret.as_bytes()).unwrap();
-        mac.input(request.as_bytes());
-        let sig = format!("{:x}", mac.result().code());
-
-        let mut req = Request::new(request.into());
-        *req.method_mut() = Method::POST;
-        *req.uri_mut() = url.parse().unwrap();
-        req.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_str(mime::APPLICATION_JSON.as_ref()).unwrap()
-        );
-        req.headers_mut().insert(
-            "x-coinmotion-apikey",
-            HeaderValue::from_str(self.api_key).unwrap()
-        );
-        req.headers_mut().insert(
-            "x-coinmotion-signature",
-            HeaderValue::from_str(sig.as_ref()).unwrap()
-        );
-
-        self.request(endpoint, req)
+        let request = serde_json::to_value(&request).unwrap();
+
+        self.request(endpoint, attempts, || {
+            let request = RequestWrapper{
+                nonce: self.nonces.next().map_err(Error::NonceError)?,
+                request: &request,
+            };
+            let request = serde_json::to_string(&request).unwrap();
+
+            let mut mac = Hmac::<Sha512>::new_varkey(self.api_secret.as_bytes()).unwrap();
+            mac.input(request.as_bytes());
+            let sig = format!("{:x}", mac.result().code());
+
+            let mut req = Request::new(request.into());
+            *req.method_mut() = Method::POST;
+            *req.uri_mut() = url.parse().unwrap();
+            req.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_str(mime::APPLICATION_JSON.as_ref()).unwrap()
+            );
+            req.headers_mut().insert(
+                "x-coinmotion-apikey",
+                HeaderValue::from_str(self.api_key).unwrap()
+            );
+            req.headers_mut().insert(
+                "x-coinmotion-signature",
+                HeaderValue::from_str(sig.as_ref()).unwrap()
+            );
+            Ok(req)
+        }).await
     }
 
-    fn get<R>(&self, endpoint: &'static str) -> impl Future<Item=R, Error=Error>
+    async fn get<R>(&self, endpoint: &'static str) -> Result<R, Error>
         where R: DeserializeOwned
     {
         let url = format!("{}{}", self.base_url, endpoint);
-        let mut req = Request::new(hyper::Body::empty());
-        *req.method_mut() = Method::GET;
-        *req.uri_mut() = url.parse().unwrap();
 
-        self.request(endpoint, req)
+        self.request(endpoint, READ_ATTEMPTS, || {
+            let mut req = Request::new(hyper::Body::empty());
+            *req.method_mut() = Method::GET;
+            *req.uri_mut() = url.parse().unwrap();
+            Ok(req)
+        }).await
     }
 
-    pub fn rates(&self) -> impl Future<Item=Rates, Error=Error> {
-        self.get("/rates")
+    pub async fn rates(&self) -> Result<Rates, Error> {
+        self.get("/rates").await
     }
 
-    pub fn balances(&self) -> impl Future<Item=Balances, Error=Error> {
-        self.post("/balances", BalancesRequest{})
+    pub async fn balances(&self) -> Result<Balances, Error> {
+        self.post("/balances", READ_ATTEMPTS, EmptyRequest{}).await
     }
 
-    pub fn sell(&self, amount: BuySellAmount) -> impl Future<Item=Trade, Error=Error> {
-        self.post("/sell", SellRequest{
-            amount_btc: if let BuySellAmount::BtcSatoshis(a) = amount {
-                Some(a)
-            } else {
-                None
-            },
-            amount_cur: if let BuySellAmount::EurCents(a) = amount {
-                Some(a)
-            } else {
-                None
-            },
-        })
-        .map(|r| {
-            debug!("Sell endpoint response: {:?}", r);
-            r
-        })
+    pub async fn sell(&self, amount: BuySellAmount) -> Result<Trade, Error> {
+        let r: Trade = self.post("/sell", WRITE_ATTEMPTS, TradeRequest::new(amount)).await?;
+        debug!(endpoint = "/sell", trade_id = r.id.as_str(); "Sell endpoint response: {:?}", r);
+        Ok(r)
+    }
+
+    pub async fn buy(&self, amount: BuySellAmount) -> Result<Trade, Error> {
+        let r: Trade = self.post("/buy", WRITE_ATTEMPTS, TradeRequest::new(amount)).await?;
+        debug!(endpoint = "/buy", trade_id = r.id.as_str(); "Buy endpoint response: {:?}", r);
+        Ok(r)
+    }
+
+    /// Every balance change of the account: trades, deposits, withdrawals and fees
+    pub async fn transactions(&self, page: HistoryPage) -> Result<Vec<Transaction>, Error> {
+        self.post("/transactions", READ_ATTEMPTS, page).await
+    }
+
+    pub async fn trades(&self, page: HistoryPage) -> Result<Vec<Trade>, Error> {
+        self.post("/trades", READ_ATTEMPTS, page).await
+    }
+
+    /// Incoming deposits, BTC deposits come with the receiving address and txid
+    pub async fn deposits(&self, page: HistoryPage) -> Result<Vec<Deposit>, Error> {
+        self.post("/deposits", READ_ATTEMPTS, page).await
+    }
+
+    pub async fn limits(&self) -> Result<Limits, Error> {
+        self.post("/limits", READ_ATTEMPTS, EmptyRequest{}).await
+    }
+
+    pub async fn fees(&self) -> Result<Fees, Error> {
+        self.post("/fees", READ_ATTEMPTS, EmptyRequest{}).await
     }
 
-    pub fn withdraw(&self, eur_cents: u64) -> impl Future<Item=Withdrawal, Error=Error> {
-        self.post("/withdraw", WithdrawRequest{
+    pub async fn withdraw(&self, eur_cents: u64) -> Result<Withdrawal, Error> {
+        let r: Withdrawal = self.post("/withdraw", WRITE_ATTEMPTS, WithdrawRequest{
             amount_cur: eur_cents,
-        })
+        }).await?;
+        debug!(endpoint = "/withdraw", ref_no = r.ref_no.as_str(); "Withdraw endpoint response: {:?}", r);
+        Ok(r)
+    }
+
+    /// Send bitcoins from the account to an external address
+    pub async fn send_btc(&self, address: &str, btc_satoshis: u64) -> Result<CryptoWithdrawal, Error> {
+        let r: CryptoWithdrawal = self.post("/crypto_withdraw", WRITE_ATTEMPTS, CryptoWithdrawRequest{
+            address: address.to_string(),
+            amount_btc: btc_satoshis,
+        }).await?;
+        debug!(endpoint = "/crypto_withdraw", txid = r.txid.as_deref(); "Crypto withdraw endpoint response: {:?}", r);
+        Ok(r)
+    }
+}
+
+/// Outcomes of the calls made to each endpoint, every failed attempt is counted
+#[derive(Debug, Default)]
+pub struct CallStats {
+    endpoints: Mutex<BTreeMap<&'static str, EndpointStats>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EndpointStats {
+    pub last_success: Option<SystemTime>,
+    /// Failed attempts, keyed by [`Error::name`](enum.Error.html#method.name)
+    pub errors: BTreeMap<&'static str, u64>,
+}
+
+impl CallStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn record_success(&self, endpoint: &'static str) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        endpoints.entry(endpoint).or_default().last_success = Some(SystemTime::now());
+    }
+
+    fn record_error(&self, endpoint: &'static str, err: &Error) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        *endpoints.entry(endpoint).or_default()
+            .errors.entry(err.name()).or_insert(0) += 1;
+    }
+
+    /// Stats of the endpoints that have been called, keyed by endpoint
+    pub fn endpoints(&self) -> BTreeMap<&'static str, EndpointStats> {
+        self.endpoints.lock().unwrap().clone()
+    }
+}
+
+async fn send<R>(client: &Client, endpoint: &'static str, req: Request<hyper::Body>) -> Result<R, Error>
+    where R: DeserializeOwned
+{
+    let res = client.request(req).await.map_err(Error::ConnectionError)?;
+    trace!(endpoint, status = res.status().as_u16(); "Coinmotion API [{}] response {}", endpoint, res.status());
+    let status = res.status();
+    let retry_after = res.headers().get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+
+    let body = hyper::body::to_bytes(res.into_body()).await
+        .map_err(Error::ConnectionError)?;
+    trace!(endpoint; "Coinmotion API [{}] response body {}", endpoint, String::from_utf8_lossy(&body));
+    parse_response(status, retry_after, &body)
+}
+
+fn parse_response<R>(status: StatusCode, retry_after: Option<Duration>, body: &[u8]) -> Result<R, Error>
+    where R: DeserializeOwned
+{
+    let response = RawResponse{
+        status,
+        body: String::from_utf8_lossy(body).into_owned(),
+    };
+
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN =>
+            return Err(Error::InvalidCredentials(response)),
+        StatusCode::TOO_MANY_REQUESTS =>
+            return Err(Error::RateLimited{retry_after, response}),
+        StatusCode::SERVICE_UNAVAILABLE =>
+            return Err(Error::Maintenance(response)),
+        s if s.is_server_error() =>
+            return Err(Error::ServerError(response)),
+        _ => {},
+    }
+
+    match serde_json::from_slice::<ResponseWrapper<R>>(body) {
+        Ok(ResponseWrapper::Response(r)) => Ok(r),
+        Ok(ResponseWrapper::Error(message)) => Err(Error::from_message(message, retry_after, response)),
+        Ok(ResponseWrapper::UnknownStatus(status)) => Err(Error::UnknownStatus{status, response}),
+        Err(err) => Err(Error::ParseError(err, response)),
     }
 }
 
@@ -145,17 +343,147 @@ pub enum BuySellAmount {
     EurCents(u64),
 }
 
-fn next_nonce() -> u64 {
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    now.as_secs() * 100 + now.subsec_nanos() as u64 / 10_000_000
+/// Status and body of a response, kept in errors for diagnostics
+#[derive(Debug, Clone)]
+pub struct RawResponse {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl fmt::Display for RawResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HTTP {}: {}", self.status.as_u16(), self.body)
+    }
 }
 
 #[derive(Debug)]
 pub enum Error {
     ConnectionError(hyper::Error),
-    ParseError(serde_json::Error),
-    BackendError(String),
-    UnknownStatus(String),
+    /// The response wasn't in the expected format
+    ParseError(serde_json::Error, RawResponse),
+    /// The API key or the signature was turned down
+    InvalidCredentials(RawResponse),
+    InsufficientFunds(String, RawResponse),
+    /// The amount is below what Coinmotion accepts
+    BelowMinimum(String, RawResponse),
+    RateLimited {
+        retry_after: Option<Duration>,
+        response: RawResponse,
+    },
+    /// Coinmotion is down for maintenance
+    Maintenance(RawResponse),
+    InvalidNonce(String, RawResponse),
+    ServerError(RawResponse),
+    /// An error reported by the API that isn't recognised
+    BackendError(String, RawResponse),
+    UnknownStatus {
+        status: String,
+        response: RawResponse,
+    },
+    /// Not attempted, Coinmotion has been failing
+    CircuitOpen,
+    NonceError(nonce::Error),
+}
+
+impl Error {
+    /// Coinmotion reports most errors with a 200 status and a message, so they are
+    /// told apart by the wording of the message
+    fn from_message(message: String, retry_after: Option<Duration>, response: RawResponse) -> Self {
+        let lower = message.to_lowercase();
+        let has = |words: &[&str]| words.iter().any(|w| lower.contains(w));
+
+        if has(&["nonce"]) {
+            Error::InvalidNonce(message, response)
+        } else if has(&["signature", "api key", "apikey", "credential", "unauthori"]) {
+            Error::InvalidCredentials(response)
+        } else if has(&["insufficient", "not enough"]) {
+            Error::InsufficientFunds(message, response)
+        } else if has(&["minimum", "too small", "too low"]) {
+            Error::BelowMinimum(message, response)
+        } else if has(&["rate limit", "too many"]) {
+            Error::RateLimited{retry_after, response}
+        } else if has(&["maintenance"]) {
+            Error::Maintenance(response)
+        } else {
+            Error::BackendError(message, response)
+        }
+    }
+
+    /// Short name of the kind of error, for logs and metrics
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Error::ConnectionError(_) => "connection_error",
+            Error::ParseError(..) => "parse_error",
+            Error::InvalidCredentials(_) => "invalid_credentials",
+            Error::InsufficientFunds(..) => "insufficient_funds",
+            Error::BelowMinimum(..) => "below_minimum",
+            Error::RateLimited{..} => "rate_limited",
+            Error::Maintenance(_) => "maintenance",
+            Error::InvalidNonce(..) => "invalid_nonce",
+            Error::ServerError(_) => "server_error",
+            Error::BackendError(..) => "backend_error",
+            Error::UnknownStatus{..} => "unknown_status",
+            Error::CircuitOpen => "circuit_open",
+            Error::NonceError(_) => "nonce_error",
+        }
+    }
+
+    /// Whether trying again later could succeed, errors reported by the API itself are final
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            *self,
+            Error::ConnectionError(_) |
+            Error::ServerError(_) |
+            Error::Maintenance(_) |
+            Error::RateLimited{..}
+        )
+    }
+
+    /// Whether the call may have been carried out regardless of the error, e.g. when
+    /// the connection dropped before a response came back
+    pub fn outcome_unknown(&self) -> bool {
+        matches!(
+            *self,
+            Error::ConnectionError(_) |
+            Error::ServerError(_) |
+            Error::ParseError(..) |
+            Error::UnknownStatus{..}
+        )
+    }
+
+    /// How long Coinmotion asked us to wait before trying again
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self {
+            Error::RateLimited{retry_after, ..} => retry_after,
+            _ => None,
+        }
+    }
+
+    /// Coinmotion turned down the nonce of the request
+    pub fn is_nonce_error(&self) -> bool {
+        matches!(*self, Error::InvalidNonce(..))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ConnectionError(ref err) => write!(f, "Connection failed: {}", err),
+            Error::ParseError(ref err, ref res) => write!(f, "Unreadable response ({}): {}", err, res),
+            Error::InvalidCredentials(ref res) => write!(f, "API key or signature turned down: {}", res),
+            Error::InsufficientFunds(ref msg, _) => write!(f, "Insufficient funds: {}", msg),
+            Error::BelowMinimum(ref msg, _) => write!(f, "Below the minimum amount: {}", msg),
+            Error::RateLimited{retry_after: Some(d), ..} => write!(f, "Rate limited for {}s", d.as_secs()),
+            Error::RateLimited{retry_after: None, ref response} => write!(f, "Rate limited: {}", response),
+            Error::Maintenance(ref res) => write!(f, "Down for maintenance: {}", res),
+            Error::InvalidNonce(ref msg, _) => write!(f, "Nonce turned down: {}", msg),
+            Error::ServerError(ref res) => write!(f, "Server error: {}", res),
+            Error::BackendError(ref msg, _) => write!(f, "{}", msg),
+            Error::UnknownStatus{ref status, ref response} => write!(f, "Unknown status {}: {}", status, response),
+            Error::CircuitOpen => write!(f, "Not attempted, Coinmotion has been failing"),
+            Error::NonceError(ref err) => write!(f, "No nonce available: {:?}", err),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -228,6 +556,7 @@ pub struct Balances {
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct Trade {
+    #[serde(deserialize_with = "deserialize_id")]
     pub id: String,
     #[serde(deserialize_with = "deserialize_big_decimal")]
     pub rate: BigDecimal,
@@ -238,6 +567,80 @@ pub struct Trade {
     pub amount_vir: BigDecimal,
 }
 
+#[derive(Deserialize, Clone, Debug)]
+pub struct Transaction {
+    #[serde(deserialize_with = "deserialize_id")]
+    pub id: String,
+    /// E.g. "trade", "deposit", "withdrawal" or "fee"
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// "EUR" or "BTC"
+    pub currency: String,
+    /// Negative when the balance decreased
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub amount: BigDecimal,
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub balance: BigDecimal,
+    pub timestamp: String,
+    /// Trade id, txid or withdrawal reference the transaction relates to
+    #[serde(default)]
+    pub reference: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Deposit {
+    #[serde(deserialize_with = "deserialize_id")]
+    pub id: String,
+    pub currency: String,
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub amount: BigDecimal,
+    /// E.g. "pending" or "completed"
+    pub status: String,
+    pub timestamp: String,
+    /// Receiving address of a BTC deposit
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub txid: Option<String>,
+    #[serde(default)]
+    pub confirmations: Option<u32>,
+}
+
+/// Account limits in EUR, per day and per month, and how much is left of them
+#[derive(Deserialize, Clone, Debug)]
+pub struct Limits {
+    pub deposit: Limit,
+    pub withdrawal: Limit,
+    pub trade: Limit,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Limit {
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub daily: BigDecimal,
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub daily_remaining: BigDecimal,
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub monthly: BigDecimal,
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub monthly_remaining: BigDecimal,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Fees {
+    /// Percentage of the traded amount
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub buy_percent: BigDecimal,
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub sell_percent: BigDecimal,
+    /// EUR charged per bank withdrawal
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub eur_withdrawal: BigDecimal,
+    /// BTC charged per crypto withdrawal
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub btc_withdrawal: BigDecimal,
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct Withdrawal {
     pub id: u64,
@@ -246,23 +649,60 @@ pub struct Withdrawal {
     pub ref_no: String,
 }
 
+#[derive(Deserialize, Clone, Debug)]
+pub struct CryptoWithdrawal {
+    pub id: u64,
+    pub address: String,
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub amount: BigDecimal,
+    #[serde(default)]
+    pub txid: Option<String>,
+}
+
 #[derive(Serialize, Debug)]
-struct BalancesRequest {
+struct EmptyRequest {
 }
 
 #[derive(Serialize, Debug)]
-struct SellRequest {
+struct TradeRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     amount_btc: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     amount_cur: Option<u64>,
 }
 
+impl TradeRequest {
+    fn new(amount: BuySellAmount) -> Self {
+        match amount {
+            BuySellAmount::BtcSatoshis(a) => TradeRequest{amount_btc: Some(a), amount_cur: None},
+            BuySellAmount::EurCents(a) => TradeRequest{amount_btc: None, amount_cur: Some(a)},
+        }
+    }
+}
+
+/// Which part of a history to fetch, the newest entries come first
+#[derive(Serialize, Debug, Default)]
+pub struct HistoryPage {
+    /// Unix timestamp of the oldest entry to include
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<u32>,
+}
+
 #[derive(Serialize, Debug)]
 struct WithdrawRequest {
     amount_cur: u64,
 }
 
+#[derive(Serialize, Debug)]
+struct CryptoWithdrawRequest {
+    address: String,
+    amount_btc: u64,
+}
+
 #[derive(Serialize, Debug)]
 struct RequestWrapper<T> {
     #[serde(serialize_with = "serialize_string")]
@@ -281,6 +721,7 @@ fn serialize_string<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::str::FromStr;
 
     #[test]
     fn deserialize_trade() {
@@ -308,4 +749,122 @@ mod tests {
         "#;
         serde_json::from_str::<Withdrawal>(json).unwrap();
     }
+
+    #[test]
+    fn deserialize_crypto_withdrawal() {
+        let json = r#"
+            {
+                "id": 40001,
+                "address": "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+                "amount": "0.0125",
+                "txid": null
+            }
+        "#;
+        serde_json::from_str::<CryptoWithdrawal>(json).unwrap();
+    }
+
+    fn fixture<R>(body: &str) -> R
+        where R: DeserializeOwned
+    {
+        parse_response(StatusCode::OK, None, body.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn deserialize_transactions() {
+        let transactions: Vec<Transaction> = fixture(include_str!("../tests/fixtures/coinmotion/transactions.json"));
+        assert_eq!(transactions[0].id, "50002");
+        assert_eq!(transactions[0].kind, "trade");
+        assert_eq!(transactions[1].id, "50001");
+        assert_eq!(transactions[1].reference, None);
+    }
+
+    #[test]
+    fn deserialize_trade_history() {
+        let trades: Vec<Trade> = fixture(include_str!("../tests/fixtures/coinmotion/trades.json"));
+        assert_eq!(trades[0].id, "360001");
+    }
+
+    #[test]
+    fn deserialize_deposits() {
+        let deposits: Vec<Deposit> = fixture(include_str!("../tests/fixtures/coinmotion/deposits.json"));
+        assert_eq!(deposits[0].id, "60001");
+        assert_eq!(deposits[0].confirmations, Some(6));
+        assert_eq!(deposits[1].address, None);
+    }
+
+    #[test]
+    fn deserialize_limits() {
+        let limits: Limits = fixture(include_str!("../tests/fixtures/coinmotion/limits.json"));
+        assert_eq!(limits.trade.daily_remaining, BigDecimal::from_str("13024.4").unwrap());
+    }
+
+    #[test]
+    fn deserialize_fees() {
+        let fees: Fees = fixture(include_str!("../tests/fixtures/coinmotion/fees.json"));
+        assert_eq!(fees.eur_withdrawal, BigDecimal::from_str(WITHDRAWAL_FEE).unwrap());
+    }
+
+    #[test]
+    fn serialize_history_page() {
+        let page = HistoryPage{
+            since: Some(1530900000),
+            ..HistoryPage::default()
+        };
+        assert_eq!(serde_json::to_string(&page).unwrap(), r#"{"since":1530900000}"#);
+    }
+
+    fn parse_error(status: StatusCode, body: &str) -> Error {
+        parse_response::<Withdrawal>(status, Some(Duration::from_secs(5)), body.as_bytes())
+            .unwrap_err()
+    }
+
+    #[test]
+    fn errors_by_status() {
+        assert_eq!(parse_error(StatusCode::UNAUTHORIZED, "").name(), "invalid_credentials");
+        assert_eq!(parse_error(StatusCode::SERVICE_UNAVAILABLE, "").name(), "maintenance");
+        assert_eq!(parse_error(StatusCode::BAD_GATEWAY, "").name(), "server_error");
+
+        let err = parse_error(StatusCode::TOO_MANY_REQUESTS, "slow down");
+        assert_eq!(err.retry_after(), Some(Duration::from_secs(5)));
+        assert!(err.is_transient());
+        match err {
+            Error::RateLimited{response, ..} => assert_eq!(response.body, "slow down"),
+            _ => unreachable!(),
+        }
+
+        assert_eq!(parse_error(StatusCode::OK, "<html>").name(), "parse_error");
+        assert!(parse_error(StatusCode::OK, "<html>").outcome_unknown());
+        assert!(!parse_error(StatusCode::SERVICE_UNAVAILABLE, "").outcome_unknown());
+    }
+
+    #[test]
+    fn errors_by_message() {
+        let error = |message: &str| parse_error(
+            StatusCode::OK,
+            &format!(r#"{{"success": false, "status": "error", "message": "{}"}}"#, message),
+        );
+        assert_eq!(error("Invalid nonce").name(), "invalid_nonce");
+        assert_eq!(error("Invalid signature").name(), "invalid_credentials");
+        assert_eq!(error("Insufficient funds").name(), "insufficient_funds");
+        assert_eq!(error("Amount is below minimum").name(), "below_minimum");
+        assert_eq!(error("Something else").name(), "backend_error");
+        assert!(!error("Insufficient funds").is_transient());
+
+        let err = parse_error(StatusCode::OK, r#"{"success": false, "status": "pending"}"#);
+        assert_eq!(err.name(), "unknown_status");
+    }
+
+    #[test]
+    fn call_stats() {
+        let stats = CallStats::new();
+        stats.record_error("/sell", &Error::CircuitOpen);
+        stats.record_error("/sell", &Error::CircuitOpen);
+        stats.record_success("/balances");
+
+        let endpoints = stats.endpoints();
+        assert_eq!(endpoints["/sell"].errors["circuit_open"], 2);
+        assert!(endpoints["/sell"].last_success.is_none());
+        assert!(endpoints["/balances"].last_success.is_some());
+        assert!(endpoints["/balances"].errors.is_empty());
+    }
 }
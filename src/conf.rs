@@ -1,14 +1,45 @@
 use std::fs::File;
 use std::io::Read;
-use toml;
+use std::str::FromStr;
+use bigdecimal::{BigDecimal, Zero};
 
-use db;
+use crate::alerts::{self, AlertsConfig, Rule};
+use crate::db;
+use crate::de::deserialize_big_decimal;
+use crate::logging::LogFormat;
+use crate::pricing::PricingConfig;
+use crate::rates::RatesConfig;
+use crate::recurring::RecurringCharge;
+use crate::worker::RECONCILE_GRACE_MINS;
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub web: WebConfig,
     pub coinmotion: CoinmotionConfig,
+    #[serde(default)]
+    pub db: DbConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub alerts: AlertsConfig,
+    /// Sender for the emails to clients, leave out to not send any
+    #[serde(default)]
+    pub mail: Option<MailConfig>,
+    #[serde(default)]
+    pub payments: PaymentsConfig,
+    #[serde(default)]
+    pub branding: BrandingConfig,
+    #[serde(default)]
+    pub rates: RatesConfig,
+    #[serde(default)]
+    pub pricing: PricingConfig,
     pub charges: Vec<db::Charge>,
+    #[serde(default)]
+    pub customers: Vec<db::Customer>,
+    #[serde(default)]
+    pub recurring: Vec<RecurringCharge>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -16,12 +47,99 @@ pub struct WebConfig {
     pub http_port: u32,
     pub hashids_salt: String,
     pub base_url: String,
+    /// Name shown to the payer's wallet as the payment recipient
+    #[serde(default)]
+    pub company_name: Option<String>,
+    /// Hex encoded secp256k1 secret key the payment requests are signed with
+    #[serde(default)]
+    pub payment_request_key: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct CoinmotionConfig {
     pub api_key: String,
     pub api_secret: String,
+    /// File the nonce high-water mark is kept in, so that nonces aren't reused after a restart
+    #[serde(default = "default_nonce_path")]
+    pub nonce_path: String,
+}
+
+fn default_nonce_path() -> String {
+    "bitcharge-nonce".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DbConfig {
+    /// JSON file where payments and other runtime data is stored
+    pub path: String,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self{
+            path: "bitcharge.json".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AdminConfig {
+    /// Bearer token for the admin API, the API is disabled without it
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MailConfig {
+    pub from: String,
+    #[serde(default = "alerts::default_sendmail")]
+    pub sendmail: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogConfig {
+    /// `human` for reading in a terminal, `json` for shipping to a log store
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self{
+            format: LogFormat::Human,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PaymentsConfig {
+    /// How many EUR a charge can be paid short, or over, and still count as paid.
+    /// Covers payers sending a little less than quoted, e.g. when their wallet takes out the fee.
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub tolerance_eur: BigDecimal,
+}
+
+impl Default for PaymentsConfig {
+    fn default() -> Self {
+        Self{
+            tolerance_eur: BigDecimal::from_str("1").unwrap(),
+        }
+    }
+}
+
+/// Company details and look of the pages shown to the payer
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct BrandingConfig {
+    pub legal_name: Option<String>,
+    pub vat_number: Option<String>,
+    pub contact_email: Option<String>,
+    /// CSS color used for accents on the payment page
+    pub primary_color: Option<String>,
+    /// Directory served at /static/, for the logo and stylesheet
+    pub static_dir: Option<String>,
+    /// Logo file name inside the static directory
+    pub logo: Option<String>,
+    /// Stylesheet file name inside the static directory, loaded after the built-in styles
+    pub stylesheet: Option<String>,
 }
 
 pub fn load() -> Config {
@@ -29,5 +147,41 @@ pub fn load() -> Config {
     let mut buf = vec![];
     f.read_to_end(&mut buf).expect("config file isn't readable");
 
-    toml::from_slice(&buf).expect("config file isn't valid")
+    let mut conf: Config = toml::from_slice(&buf).expect("config file isn't valid");
+    for charge in conf.charges.iter_mut() {
+        if charge.id >= db::FIRST_ISSUED_CHARGE_ID {
+            panic!("charge {} has an id that is reserved for recurring charges, use one below {}",
+                   charge.id, db::FIRST_ISSUED_CHARGE_ID);
+        }
+        match charge.line_items_total() {
+            Some(total) => if charge.eur_amount.is_zero() {
+                charge.eur_amount = total;
+            } else if charge.eur_amount != total {
+                panic!("charge {} eur_amount {} doesn't match its line items total {}",
+                       charge.id, charge.eur_amount, total);
+            },
+            None => if charge.eur_amount.is_zero() {
+                panic!("charge {} has neither an eur_amount nor line items", charge.id);
+            },
+        }
+    }
+    for template in conf.recurring.iter() {
+        if template.btc_addresses.is_empty() {
+            panic!("recurring charge {} has no btc_addresses", template.id);
+        }
+    }
+    for customer in conf.customers.iter() {
+        if customer.currency != "EUR" {
+            panic!("customer {} has currency {}, only EUR is supported", customer.id, customer.currency);
+        }
+    }
+    for rule in conf.alerts.rules.iter() {
+        // A withdrawal is confirmed or marked failed by then, so the rule would never fire
+        if let Rule::WithdrawalPending{minutes} = *rule {
+            if minutes >= RECONCILE_GRACE_MINS {
+                panic!("withdrawal_pending alert has minutes = {}, it has to be less than {}", minutes, RECONCILE_GRACE_MINS);
+            }
+        }
+    }
+    conf
 }
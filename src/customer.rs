@@ -0,0 +1,95 @@
+use hyper::{Response, Body, HeaderMap, StatusCode};
+use hyper::header::ACCEPT_LANGUAGE;
+use askama::Template;
+use gotham::helpers::http::response::create_response;
+use gotham::state::{FromState, State};
+
+use crate::conf::BrandingConfig;
+use crate::db::ChargeStatus;
+use crate::i18n::{self, Messages};
+use crate::middleware::Env;
+use crate::web::{self, CustomerPath, LangQuery};
+
+#[derive(Template)]
+#[template(path = "customer.html")]
+struct CustomerTemplate<'a> {
+    lang: &'a str,
+    t: &'a Messages,
+    branding: &'a BrandingConfig,
+    static_url: String,
+    name: &'a str,
+    outstanding: Vec<InvoiceRow>,
+    paid: Vec<InvoiceRow>,
+}
+
+struct InvoiceRow {
+    invoice_id: String,
+    eur_amount: String,
+    due_date: String,
+    url: String,
+}
+
+/// Page listing the customer's outstanding and paid invoices, so that
+/// the client can find everything from one link.
+pub fn get_customer_page(state: State) -> (State, Response<Body>) {
+    let res = {
+        let env = Env::borrow_from(&state);
+        let path = CustomerPath::borrow_from(&state);
+        let query = LangQuery::borrow_from(&state);
+
+        if let Some(customer) = web::find_customer(env, &path.customer_id) {
+            let lang = i18n::negotiate(
+                query.lang.as_deref(),
+                HeaderMap::borrow_from(&state).get(ACCEPT_LANGUAGE)
+                    .and_then(|v| v.to_str().ok()),
+                customer.language.as_deref(),
+            );
+
+            let mut outstanding = vec![];
+            let mut paid = vec![];
+            for charge in env.db.charges_for_customer(customer.id) {
+                let status = env.db.charge_status(&charge);
+                let url = web::pay_url(env, &charge);
+                if status.is_payable() {
+                    outstanding.push(InvoiceRow{
+                        eur_amount: i18n::format_decimal(
+                            &env.db.amount_due(&charge).with_scale(2).to_string(), lang),
+                        due_date: charge.due_date.map(|d| d.to_string()).unwrap_or_default(),
+                        url: url.into_string(),
+                        invoice_id: charge.invoice_id,
+                    });
+                } else if status == ChargeStatus::Paid {
+                    paid.push(InvoiceRow{
+                        eur_amount: i18n::format_decimal(
+                            &charge.eur_amount.with_scale(2).to_string(), lang),
+                        due_date: String::new(),
+                        url: url.join("receipt.pdf")
+                            .expect("url construction failed for receipt")
+                            .into_string(),
+                        invoice_id: charge.invoice_id,
+                    });
+                }
+            }
+
+            let html = CustomerTemplate{
+                lang: lang.code(),
+                t: env.catalogs.get(lang),
+                branding: &env.branding,
+                static_url: web::static_url(env),
+                name: customer.name.as_str(),
+                outstanding,
+                paid,
+            }.render().unwrap();
+            create_response(
+                &state,
+                StatusCode::OK,
+                mime::TEXT_HTML,
+                html.into_bytes(),
+            )
+        } else {
+            web::not_found(&state)
+        }
+    };
+
+    (state, res)
+}
@@ -1,35 +1,1249 @@
-use bigdecimal::BigDecimal;
+//! Charges, customers and everything recorded about them at runtime: payments,
+//! cancellations, refunds and the ledger, along with the sells and withdrawals
+//! made on Coinmotion.
 
-use de::deserialize_big_decimal;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+use bigdecimal::{BigDecimal, Zero};
+use chrono::{DateTime, NaiveDate, Utc};
+
+use crate::de::{deserialize_big_decimal, deserialize_opt_big_decimal};
+use crate::ser::{serialize_big_decimal, serialize_opt_big_decimal};
+
+/// Charges issued at runtime are numbered from here on, configured charges stay below it
+pub const FIRST_ISSUED_CHARGE_ID: u64 = 1_000_000;
 
 pub struct Database {
     charges: Vec<Charge>,
+    customers: Vec<Customer>,
+    path: PathBuf,
+    state: RwLock<State>,
+    /// How many EUR a charge can be paid short, or over, and still count as paid
+    tolerance: BigDecimal,
+}
+
+/// Everything that is recorded at runtime, persisted as JSON
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct State {
+    /// Charges issued at runtime, in addition to the configured ones
+    #[serde(default)]
+    charges: Vec<Charge>,
+    #[serde(default)]
+    payments: Vec<Payment>,
+    /// Payments acknowledged to a wallet but not seen on chain yet, they don't count
+    /// towards the charge until they are recorded as payments
+    #[serde(default)]
+    pending_payments: Vec<Payment>,
+    #[serde(default)]
+    cancellations: Vec<Cancellation>,
+    #[serde(default)]
+    ledger: Vec<LedgerEntry>,
+    #[serde(default)]
+    refunds: Vec<Refund>,
+    #[serde(default)]
+    sales: Vec<Sale>,
+    #[serde(default)]
+    exchanges: Vec<ExchangeAction>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    /// Every deposit address given for a new charge is already used by another charge
+    NoFreeAddress,
+    /// Charges that have received payments can't be cancelled or voided, refund them instead
+    HasPayments,
+    /// The transaction has already been recorded as a payment for the charge
+    DuplicatePayment,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "Database file can't be read or written: {}", err),
+            Error::Parse(ref err) => write!(f, "Database file isn't valid: {}", err),
+            Error::NoFreeAddress => write!(f, "Every deposit address is already in use"),
+            Error::HasPayments => write!(f, "Charge has received payments"),
+            Error::DuplicatePayment => write!(f, "Payment has already been recorded"),
+        }
+    }
 }
 
 impl Database {
-    pub fn new(charges: Vec<Charge>) -> Self {
-        Self{
+    pub fn open(charges: Vec<Charge>, customers: Vec<Customer>, path: &str, tolerance: BigDecimal) -> Result<Self, Error> {
+        let path = PathBuf::from(path);
+        let state = match File::open(&path) {
+            Ok(mut f) => {
+                let mut buf = vec![];
+                f.read_to_end(&mut buf).map_err(Error::Io)?;
+                serde_json::from_slice(&buf).map_err(Error::Parse)?
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(err) => return Err(Error::Io(err)),
+        };
+
+        Ok(Self{
             charges,
-        }
+            customers,
+            path,
+            state: RwLock::new(state),
+            tolerance,
+        })
     }
-    pub fn get_charge_by_id(&self, charge_id: u64) -> Option<&Charge> {
+
+    pub fn get_charge_by_id(&self, charge_id: u64) -> Option<Charge> {
+        let state = self.state.read().unwrap();
         let mut it = self.charges.iter()
+            .chain(state.charges.iter())
             .filter(|c| c.id == charge_id);
-        it.next()
+        it.next().cloned()
+    }
+
+    pub fn charges(&self) -> Vec<Charge> {
+        let state = self.state.read().unwrap();
+        self.charges.iter()
+            .chain(state.charges.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_customer_by_id(&self, customer_id: u64) -> Option<&Customer> {
+        self.customers.iter()
+            .find(|c| c.id == customer_id)
+    }
+
+    pub fn customers(&self) -> &[Customer] {
+        self.customers.as_slice()
+    }
+
+    pub fn charges_for_customer(&self, customer_id: u64) -> Vec<Charge> {
+        self.charges().into_iter()
+            .filter(|c| c.customer_id == Some(customer_id))
+            .collect()
+    }
+
+    /// Issue a new charge with the next free id and the first of the addresses that
+    /// no other charge uses, returns `None` when a charge for the same recurring
+    /// template and period has already been issued.
+    pub fn issue_charge(&self, mut charge: Charge, addresses: &[String]) -> Result<Option<Charge>, Error> {
+        self.update(|state| {
+            if let Some(ref recurrence) = charge.recurrence {
+                let issued = state.charges.iter()
+                    .filter_map(|c| c.recurrence.as_ref())
+                    .any(|r| r.template == recurrence.template && r.period == recurrence.period);
+                if issued {
+                    return Ok(None);
+                }
+            }
+
+            charge.btc_address = addresses.iter()
+                .find(|address| !self.charges.iter().chain(state.charges.iter()).any(|c| &c.btc_address == *address))
+                .ok_or(Error::NoFreeAddress)?
+                .clone();
+            charge.id = state.charges.iter()
+                .map(|c| c.id)
+                .max()
+                .unwrap_or(0)
+                .max(FIRST_ISSUED_CHARGE_ID - 1) + 1;
+            state.charges.push(charge.clone());
+            Ok(Some(charge))
+        })
+    }
+
+    /// Mark the client as notified of the issued charge
+    pub fn mark_notified(&self, charge_id: u64) -> Result<(), Error> {
+        self.update(|state| {
+            for charge in state.charges.iter_mut().filter(|c| c.id == charge_id) {
+                if let Some(ref mut recurrence) = charge.recurrence {
+                    recurrence.notified = true;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Mark the payment link of the issued charge as emailed to the client
+    pub fn mark_emailed(&self, charge_id: u64) -> Result<(), Error> {
+        self.update(|state| {
+            for charge in state.charges.iter_mut().filter(|c| c.id == charge_id) {
+                if let Some(ref mut recurrence) = charge.recurrence {
+                    recurrence.emailed = true;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn payments_for(&self, charge_id: u64) -> Vec<Payment> {
+        self.state.read().unwrap().payments.iter()
+            .filter(|p| p.charge_id == charge_id)
+            .cloned()
+            .collect()
+    }
+
+    /// EUR value of all the payments for the charge, at the rates they were quoted at
+    pub fn paid_eur(&self, charge_id: u64) -> BigDecimal {
+        let state = self.state.read().unwrap();
+        paid_eur(&state, charge_id)
+    }
+
+    /// EUR amount the payer still has to pay for the charge
+    pub fn amount_due(&self, charge: &Charge) -> BigDecimal {
+        let due = charge.eur_amount.clone() - self.paid_eur(charge.id);
+        if due > BigDecimal::zero() {
+            due
+        } else {
+            BigDecimal::zero()
+        }
+    }
+
+    pub fn charge_status(&self, charge: &Charge) -> ChargeStatus {
+        let state = self.state.read().unwrap();
+        self.status_of(&state, charge)
+    }
+
+    fn status_of(&self, state: &State, charge: &Charge) -> ChargeStatus {
+        let cancellation = state.cancellations.iter()
+            .rev()
+            .find(|c| c.charge_id == charge.id);
+        if let Some(c) = cancellation {
+            return match c.kind {
+                CancellationKind::Cancel => ChargeStatus::Cancelled,
+                CancellationKind::Void => ChargeStatus::Voided,
+            };
+        }
+
+        let has_payments = state.payments.iter().any(|p| p.charge_id == charge.id);
+        let short = charge.eur_amount.clone() - paid_eur(state, charge.id);
+        if has_payments && short <= self.tolerance {
+            ChargeStatus::Paid
+        } else if charge.is_expired() {
+            ChargeStatus::Expired
+        } else if has_payments {
+            ChargeStatus::PartiallyPaid
+        } else {
+            ChargeStatus::Open
+        }
+    }
+
+    /// Cancel or void a charge that hasn't been paid anything, paid charges need their
+    /// payments refunded instead
+    pub fn cancel_charge(&self, charge_id: u64, kind: CancellationKind) -> Result<(), Error> {
+        self.update(|state| {
+            if state.payments.iter().any(|p| p.charge_id == charge_id) {
+                return Err(Error::HasPayments);
+            }
+            state.cancellations.push(Cancellation{
+                charge_id,
+                kind,
+                cancelled_at: Utc::now(),
+            });
+            Ok(())
+        })
+    }
+
+    pub fn pending_payments_for(&self, charge_id: u64) -> Vec<Payment> {
+        self.state.read().unwrap().pending_payments.iter()
+            .filter(|p| p.charge_id == charge_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Remember a payment that a wallet has submitted for broadcasting, so that it can be
+    /// matched when it arrives. Submitting the same transaction again changes nothing.
+    pub fn record_pending_payment(&self, payment: Payment) -> Result<(), Error> {
+        self.update(|state| {
+            let known = state.pending_payments.iter().chain(state.payments.iter())
+                .any(|p| p.charge_id == payment.charge_id && p.txid == payment.txid);
+            if !known {
+                state.pending_payments.push(payment);
+            }
+            Ok(())
+        })
+    }
+
+    /// Record a payment for the charge, along with its ledger entries. Payments for charges
+    /// that are no longer payable get flagged for review and anything paid on top of the charge
+    /// amount is recorded as an overpayment to refund. A transaction can only be recorded once
+    /// for a charge.
+    pub fn record_payment(&self, charge: &Charge, mut payment: Payment) -> Result<Payment, Error> {
+        self.update(|state| {
+            if state.payments.iter().any(|p| p.charge_id == charge.id && p.txid == payment.txid) {
+                return Err(Error::DuplicatePayment);
+            }
+            let status = self.status_of(state, charge);
+            payment.needs_review = match status {
+                ChargeStatus::Open | ChargeStatus::PartiallyPaid | ChargeStatus::Paid => false,
+                ChargeStatus::Expired | ChargeStatus::Cancelled | ChargeStatus::Voided => true,
+            };
+
+            let payment_eur = payment.eur_value();
+            let overpaid_eur = match status {
+                // Nothing was owed on these, so all of it has to go back
+                ChargeStatus::Cancelled | ChargeStatus::Voided => payment_eur.clone(),
+                _ => {
+                    let total = paid_eur(state, charge.id) + payment_eur.clone();
+                    let excess = total - charge.eur_amount.clone();
+                    if excess > self.tolerance {
+                        excess
+                    } else {
+                        BigDecimal::zero()
+                    }
+                },
+            };
+
+            let now = Utc::now();
+            state.ledger.push(LedgerEntry{
+                charge_id: charge.id,
+                kind: LedgerKind::Payment,
+                btc_amount: payment.btc_amount.clone(),
+                eur_amount: payment_eur.with_scale(2),
+                vat_eur: charge.vat_share(&payment_eur),
+                reference: payment.txid.clone(),
+                created_at: now,
+            });
+            if overpaid_eur > BigDecimal::zero() {
+                let overpaid_btc = (overpaid_eur.clone() / payment.btc_rate.clone()).with_scale(8);
+                state.ledger.push(LedgerEntry{
+                    charge_id: charge.id,
+                    kind: LedgerKind::Overpayment,
+                    btc_amount: overpaid_btc,
+                    eur_amount: overpaid_eur.with_scale(2),
+                    vat_eur: None,
+                    reference: payment.txid.clone(),
+                    created_at: now,
+                });
+            }
+
+            state.pending_payments.retain(|p| p.charge_id != charge.id || p.txid != payment.txid);
+            state.payments.push(payment.clone());
+            Ok(payment)
+        })
+    }
+
+    pub fn ledger(&self) -> Vec<LedgerEntry> {
+        self.state.read().unwrap().ledger.clone()
+    }
+
+    pub fn sales(&self) -> Vec<Sale> {
+        self.state.read().unwrap().sales.clone()
+    }
+
+    pub fn exchanges(&self) -> Vec<ExchangeAction> {
+        self.state.read().unwrap().exchanges.clone()
+    }
+
+    /// The sell or withdrawal that hasn't been settled yet, there is at most one
+    pub fn unfinished_exchange(&self) -> Option<ExchangeAction> {
+        self.state.read().unwrap().exchanges.iter()
+            .find(|e| e.status.is_unfinished())
+            .cloned()
+    }
+
+    /// Record the sell or withdrawal just before it is sent to Coinmotion, from here
+    /// on it may have been carried out even if no response comes back
+    pub fn record_exchange(&self, kind: ExchangeKind, amount: u64, balance_before: BigDecimal) -> Result<ExchangeAction, Error> {
+        self.update(|state| {
+            let action = ExchangeAction{
+                id: state.exchanges.iter().map(|e| e.id).max().unwrap_or(0) + 1,
+                kind,
+                amount,
+                balance_before,
+                status: ExchangeStatus::Submitted,
+                submitted_at: Utc::now(),
+                reference: None,
+                failure: None,
+            };
+            state.exchanges.push(action.clone());
+            Ok(action)
+        })
+    }
+
+    /// Mark the action as carried out, a confirmed sell is recorded as a sale at the same time
+    pub fn confirm_exchange(&self, id: u64, reference: Option<&str>, sale: Option<Sale>) -> Result<(), Error> {
+        self.update(|state| {
+            for action in state.exchanges.iter_mut().filter(|e| e.id == id) {
+                action.status = ExchangeStatus::Confirmed;
+                action.reference = reference.map(|r| r.to_string());
+            }
+            if let Some(sale) = sale {
+                state.sales.push(sale);
+            }
+            Ok(())
+        })
+    }
+
+    pub fn fail_exchange(&self, id: u64, failure: &str) -> Result<(), Error> {
+        self.update(|state| {
+            for action in state.exchanges.iter_mut().filter(|e| e.id == id) {
+                action.status = ExchangeStatus::Failed;
+                action.failure = Some(failure.to_string());
+            }
+            Ok(())
+        })
+    }
+
+    /// Forget an action that is known not to have been carried out and isn't worth
+    /// keeping, e.g. a sell below the Coinmotion minimum
+    pub fn discard_exchange(&self, id: u64) -> Result<(), Error> {
+        self.update(|state| {
+            state.exchanges.retain(|e| e.id != id);
+            Ok(())
+        })
+    }
+
+    /// BTC overpaid for the charge that the payer hasn't asked back yet
+    pub fn refundable_btc(&self, charge_id: u64) -> BigDecimal {
+        let state = self.state.read().unwrap();
+        refundable_btc(&state, charge_id)
+    }
+
+    /// BTC that is owed back to payers, either not yet requested or not yet sent.
+    /// A failed refund stays reserved until it is sent or rejected, and rejecting a
+    /// refund releases what was left unrequested on its charge, until the payer asks
+    /// for it again.
+    pub fn reserved_btc(&self) -> BigDecimal {
+        let state = self.state.read().unwrap();
+        let unsent = state.refunds.iter()
+            .filter(|r| r.status.is_unsent())
+            .fold(BigDecimal::zero(), |acc, r| acc + r.btc_amount.clone());
+
+        let mut charge_ids: Vec<u64> = state.ledger.iter()
+            .filter(|e| e.kind == LedgerKind::Overpayment)
+            .map(|e| e.charge_id)
+            .collect();
+        charge_ids.sort();
+        charge_ids.dedup();
+        let unrequested = charge_ids.into_iter()
+            .filter(|&id| !state.refunds.iter().any(|r| r.charge_id == id && !r.status.is_outstanding()))
+            .map(|id| refundable_btc(&state, id))
+            .filter(|btc| *btc > BigDecimal::zero())
+            .fold(BigDecimal::zero(), |acc, btc| acc + btc);
+
+        unsent + unrequested
     }
 
-    pub fn charges(&self) -> &[Charge] {
-        self.charges.as_slice()
+    pub fn refunds(&self) -> Vec<Refund> {
+        self.state.read().unwrap().refunds.clone()
     }
+
+    pub fn refunds_for(&self, charge_id: u64) -> Vec<Refund> {
+        self.state.read().unwrap().refunds.iter()
+            .filter(|r| r.charge_id == charge_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Request everything refundable for the charge to be sent to the address,
+    /// returns `None` when there is nothing to refund.
+    pub fn request_refund(&self, charge_id: u64, address: &str) -> Result<Option<Refund>, Error> {
+        self.update(|state| {
+            let btc_amount = refundable_btc(state, charge_id);
+            if btc_amount <= BigDecimal::zero() {
+                return Ok(None);
+            }
+
+            let refund = Refund{
+                id: state.refunds.iter().map(|r| r.id).max().unwrap_or(0) + 1,
+                charge_id,
+                address: address.to_string(),
+                btc_amount,
+                status: RefundStatus::Requested,
+                requested_at: Utc::now(),
+                reference: None,
+                failure: None,
+            };
+            state.refunds.push(refund.clone());
+            Ok(Some(refund))
+        })
+    }
+
+    /// Move the refund to a new status, returns `None` if the refund doesn't
+    /// exist or isn't in the expected status.
+    pub fn update_refund(&self, refund_id: u64, from: RefundStatus, to: RefundStatus) -> Result<Option<Refund>, Error> {
+        self.update(|state| {
+            let refund = {
+                let refund = state.refunds.iter_mut()
+                    .find(|r| r.id == refund_id && r.status == from);
+                match refund {
+                    Some(refund) => {
+                        refund.status = to;
+                        refund.clone()
+                    },
+                    None => return Ok(None),
+                }
+            };
+            Ok(Some(refund))
+        })
+    }
+
+    /// Record why sending the refund didn't go through and move it to `status`, which
+    /// stays `Sending` when the withdrawal may have been made regardless
+    pub fn fail_refund(&self, refund_id: u64, status: RefundStatus, failure: &str) -> Result<(), Error> {
+        self.update(|state| {
+            for refund in state.refunds.iter_mut().filter(|r| r.id == refund_id) {
+                refund.status = status;
+                refund.failure = Some(failure.to_string());
+            }
+            Ok(())
+        })
+    }
+
+    /// Mark an approved refund as sent and record it in the ledger
+    pub fn complete_refund(&self, refund_id: u64, reference: &str, btc_rate: BigDecimal) -> Result<(), Error> {
+        self.update(|state| {
+            let refund = {
+                let refund = state.refunds.iter_mut()
+                    .find(|r| r.id == refund_id);
+                match refund {
+                    Some(refund) => {
+                        refund.status = RefundStatus::Sent;
+                        refund.reference = Some(reference.to_string());
+                        refund.failure = None;
+                        refund.clone()
+                    },
+                    None => return Ok(()),
+                }
+            };
+            state.ledger.push(LedgerEntry{
+                charge_id: refund.charge_id,
+                kind: LedgerKind::Refund,
+                eur_amount: (refund.btc_amount.clone() * btc_rate).with_scale(2),
+                btc_amount: refund.btc_amount,
+                vat_eur: None,
+                reference: reference.to_string(),
+                created_at: Utc::now(),
+            });
+            Ok(())
+        })
+    }
+
+    /// Make the changes on a copy of the state, which replaces the state only once it
+    /// has been saved, so that a failed save leaves nothing behind in memory either
+    fn update<T, F>(&self, change: F) -> Result<T, Error>
+        where F: FnOnce(&mut State) -> Result<T, Error>
+    {
+        let mut state = self.state.write().unwrap();
+        let mut changed = state.clone();
+        let result = change(&mut changed)?;
+        self.save(&changed)?;
+        *state = changed;
+        Ok(result)
+    }
+
+    fn save(&self, state: &State) -> Result<(), Error> {
+        let buf = serde_json::to_vec_pretty(state).map_err(Error::Parse)?;
+
+        // Write to a temporary file first, so that a crash can't leave
+        // behind a half-written database
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut f = File::create(&tmp_path).map_err(Error::Io)?;
+            f.write_all(&buf).map_err(Error::Io)?;
+            f.sync_all().map_err(Error::Io)?;
+        }
+        fs::rename(&tmp_path, &self.path).map_err(Error::Io)
+    }
+}
+
+fn refundable_btc(state: &State, charge_id: u64) -> BigDecimal {
+    let overpaid = state.ledger.iter()
+        .filter(|e| e.charge_id == charge_id && e.kind == LedgerKind::Overpayment)
+        .fold(BigDecimal::zero(), |acc, e| acc + e.btc_amount.clone());
+    let requested = state.refunds.iter()
+        .filter(|r| r.charge_id == charge_id && r.status.is_outstanding())
+        .fold(BigDecimal::zero(), |acc, r| acc + r.btc_amount.clone());
+    overpaid - requested
+}
+
+fn paid_eur(state: &State, charge_id: u64) -> BigDecimal {
+    state.payments.iter()
+        .filter(|p| p.charge_id == charge_id)
+        .fold(BigDecimal::zero(), |acc, p| acc + p.eur_value())
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Charge {
     pub id: u64,
     pub invoice_id: String,
-    #[serde(deserialize_with = "deserialize_big_decimal")]
+    /// Can be left out when the charge has line items, it's then their total
+    #[serde(default = "BigDecimal::zero", serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
     pub eur_amount: BigDecimal,
+    /// Breakdown of the amount for invoices with several lines or VAT
+    #[serde(default)]
+    pub line_items: Vec<LineItem>,
     pub btc_address: String,
+    /// Lightning invoice offered to the payer as an alternative
+    #[serde(default)]
+    pub lightning_invoice: Option<String>,
+    /// Default payment page language (en, et, fi or de)
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Last day (in UTC) the charge can be paid on
+    #[serde(default)]
+    pub due_date: Option<NaiveDate>,
+    /// Set for charges issued from a recurring charge template
+    #[serde(default)]
+    pub recurrence: Option<Recurrence>,
+    #[serde(default)]
+    pub customer_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recurrence {
+    pub template: String,
+    /// Start date of the period the charge was issued for
+    pub period: NaiveDate,
+    /// Whether the payment link has been sent to the webhook
+    #[serde(default)]
+    pub notified: bool,
+    /// Whether the payment link has been emailed to the client
+    #[serde(default)]
+    pub emailed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LineItem {
+    pub description: String,
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub net_eur: BigDecimal,
+    /// VAT rate in percent, e.g. "24"
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub vat_rate: BigDecimal,
+}
+
+impl LineItem {
+    pub fn vat_eur(&self) -> BigDecimal {
+        let percent = BigDecimal::from_str("0.01").unwrap();
+        round_cents(self.net_eur.clone() * self.vat_rate.clone() * percent)
+    }
+}
+
+/// Net amount and VAT of the line items that share a VAT rate
+#[derive(Debug, Clone, PartialEq)]
+pub struct VatSummary {
+    pub vat_rate: BigDecimal,
+    pub net_eur: BigDecimal,
+    pub vat_eur: BigDecimal,
+}
+
+/// Round to whole cents, halves away from zero
+pub fn round_cents(value: BigDecimal) -> BigDecimal {
+    let half = BigDecimal::from_str("0.005").unwrap();
+    if value < BigDecimal::zero() {
+        (value - half).with_scale(2)
+    } else {
+        (value + half).with_scale(2)
+    }
+}
+
+impl Charge {
+    /// Total of the line items including VAT, `None` for single-amount charges
+    pub fn line_items_total(&self) -> Option<BigDecimal> {
+        if self.line_items.is_empty() {
+            return None;
+        }
+        Some(self.line_items.iter()
+            .fold(BigDecimal::zero(), |acc, l| acc + l.net_eur.clone() + l.vat_eur()))
+    }
+
+    /// VAT grouped by rate, in the order the rates first appear in
+    pub fn vat_breakdown(&self) -> Vec<VatSummary> {
+        let mut summaries: Vec<VatSummary> = vec![];
+        for line in self.line_items.iter() {
+            let pos = summaries.iter().position(|s| s.vat_rate == line.vat_rate);
+            match pos {
+                Some(pos) => {
+                    let summary = &mut summaries[pos];
+                    summary.net_eur = summary.net_eur.clone() + line.net_eur.clone();
+                    summary.vat_eur = summary.vat_eur.clone() + line.vat_eur();
+                },
+                None => summaries.push(VatSummary{
+                    vat_rate: line.vat_rate.clone(),
+                    net_eur: line.net_eur.clone(),
+                    vat_eur: line.vat_eur(),
+                }),
+            }
+        }
+        summaries
+    }
+
+    /// Part of the EUR amount that is VAT, for charges with line items
+    pub fn vat_share(&self, eur_amount: &BigDecimal) -> Option<BigDecimal> {
+        let total = self.line_items_total()?;
+        if total == BigDecimal::zero() {
+            return None;
+        }
+        let vat = self.line_items.iter()
+            .fold(BigDecimal::zero(), |acc, l| acc + l.vat_eur());
+        Some(round_cents(eur_amount.clone() * vat / total))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.due_date {
+            Some(due_date) => Utc::now().naive_utc().date() > due_date,
+            None => false,
+        }
+    }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Customer {
+    pub id: u64,
+    pub name: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Default payment page language for the customer's charges
+    #[serde(default)]
+    pub language: Option<String>,
+    /// Currency the customer is invoiced in, only EUR is supported for now
+    #[serde(default = "default_currency")]
+    pub currency: String,
+}
+
+fn default_currency() -> String {
+    "EUR".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChargeStatus {
+    Open,
+    PartiallyPaid,
+    Paid,
+    Expired,
+    Cancelled,
+    Voided,
+}
+
+impl ChargeStatus {
+    /// Whether the payer should still be quoted an amount to pay
+    pub fn is_payable(&self) -> bool {
+        *self == ChargeStatus::Open || *self == ChargeStatus::PartiallyPaid
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ChargeStatus::Open => "open",
+            ChargeStatus::PartiallyPaid => "partially paid",
+            ChargeStatus::Paid => "paid",
+            ChargeStatus::Expired => "expired",
+            ChargeStatus::Cancelled => "cancelled",
+            ChargeStatus::Voided => "voided",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CancellationKind {
+    /// The client no longer needs to pay
+    Cancel,
+    /// The charge was issued in error
+    Void,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Cancellation {
+    charge_id: u64,
+    kind: CancellationKind,
+    cancelled_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Payment {
+    pub charge_id: u64,
+    pub txid: String,
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub btc_amount: BigDecimal,
+    /// EUR/BTC rate the payment was quoted at
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub btc_rate: BigDecimal,
+    pub received_at: DateTime<Utc>,
+    /// Set for payments that arrived for an expired or cancelled charge
+    #[serde(default)]
+    pub needs_review: bool,
+}
+
+impl Payment {
+    pub fn eur_value(&self) -> BigDecimal {
+        self.btc_amount.clone() * self.btc_rate.clone()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerKind {
+    /// Bitcoins received from the payer
+    Payment,
+    /// Part of a payment that is owed back to the payer
+    Overpayment,
+    /// Bitcoins sent back to the payer
+    Refund,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundStatus {
+    /// Payer has submitted an address, waiting for approval
+    Requested,
+    /// Approved by an admin, waiting to be sent by the worker
+    Approved,
+    /// The worker has started sending it, if it stays here the outcome is unknown and
+    /// Coinmotion has to be checked before approving it again
+    Sending,
+    Sent,
+    /// Written off by an admin, the amount is no longer held back
+    Rejected,
+    /// Sending failed, stays reserved until it is approved again to retry or rejected
+    Failed,
+}
+
+impl RefundStatus {
+    /// Whether the refund still holds on to the refundable amount
+    pub fn is_outstanding(&self) -> bool {
+        match *self {
+            RefundStatus::Requested | RefundStatus::Approved | RefundStatus::Sending |
+            RefundStatus::Sent | RefundStatus::Failed => true,
+            RefundStatus::Rejected => false,
+        }
+    }
+
+    /// Whether the refund is still to be sent, or rejected
+    pub fn is_unsent(&self) -> bool {
+        match *self {
+            RefundStatus::Requested | RefundStatus::Approved | RefundStatus::Sending |
+            RefundStatus::Failed => true,
+            RefundStatus::Sent | RefundStatus::Rejected => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Refund {
+    pub id: u64,
+    pub charge_id: u64,
+    pub address: String,
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub btc_amount: BigDecimal,
+    pub status: RefundStatus,
+    pub requested_at: DateTime<Utc>,
+    /// Exchange reference of the sent refund
+    pub reference: Option<String>,
+    /// Why sending the refund failed, for whoever looks into it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub charge_id: u64,
+    pub kind: LedgerKind,
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub btc_amount: BigDecimal,
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub eur_amount: BigDecimal,
+    /// VAT included in the payment, for charges with line items
+    #[serde(default, skip_serializing_if = "Option::is_none",
+            serialize_with = "serialize_opt_big_decimal", deserialize_with = "deserialize_opt_big_decimal")]
+    pub vat_eur: Option<BigDecimal>,
+    /// Transaction ID or other identifier the entry relates to
+    pub reference: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// BTC sold on Coinmotion by the worker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sale {
+    pub trade_id: String,
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub btc_amount: BigDecimal,
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub eur_amount: BigDecimal,
+    /// EUR/BTC rate the BTC was sold at
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub btc_rate: BigDecimal,
+    pub sold_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExchangeKind {
+    Sell,
+    Withdrawal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExchangeStatus {
+    /// Sent to Coinmotion, but no confirmation has come back
+    Submitted,
+    Confirmed,
+    /// Turned down by Coinmotion, or not found there after it was submitted, for a
+    /// human to double-check
+    Failed,
+}
+
+impl ExchangeStatus {
+    /// Whether the outcome still has to be found out
+    pub fn is_unfinished(&self) -> bool {
+        match *self {
+            ExchangeStatus::Submitted => true,
+            ExchangeStatus::Confirmed | ExchangeStatus::Failed => false,
+        }
+    }
+}
+
+/// Sell or withdrawal made by the worker, recorded before it is sent so that its
+/// outcome can be found out after a crash or a lost response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExchangeAction {
+    pub id: u64,
+    pub kind: ExchangeKind,
+    /// Satoshis to sell, or EUR cents to withdraw
+    pub amount: u64,
+    /// Available BTC for a sell, or EUR for a withdrawal, when the action was recorded
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub balance_before: BigDecimal,
+    pub status: ExchangeStatus,
+    pub submitted_at: DateTime<Utc>,
+    /// Trade ID or withdrawal reference on Coinmotion
+    #[serde(default)]
+    pub reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::test_util::Scratch;
+
+    fn dec(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    fn charge() -> Charge {
+        Charge{
+            id: 1,
+            invoice_id: "2018-0012".to_string(),
+            eur_amount: dec("100"),
+            line_items: vec![],
+            btc_address: "1Archive1n2C579dMsAu3iC6tWzuQJz8dN".to_string(),
+            lightning_invoice: None,
+            language: None,
+            due_date: None,
+            recurrence: None,
+            customer_id: Some(1),
+        }
+    }
+
+    fn customer() -> Customer {
+        Customer{
+            id: 1,
+            name: "ACME".to_string(),
+            email: None,
+            language: None,
+            currency: "EUR".to_string(),
+        }
+    }
 
+    fn payment(txid: &str, btc_amount: &str) -> Payment {
+        Payment{
+            charge_id: 1,
+            txid: txid.to_string(),
+            btc_amount: dec(btc_amount),
+            btc_rate: dec("5000"),
+            received_at: Utc::now(),
+            needs_review: false,
+        }
+    }
+
+    fn open_db(scratch: &Scratch) -> Database {
+        Database::open(vec![charge()], vec![customer()], &scratch.path("db.json"), dec("1")).unwrap()
+    }
+
+    #[test]
+    fn partial_payments_are_summed() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        db.record_payment(&charge, payment("a", "0.01")).unwrap();
+        assert_eq!(db.charge_status(&charge), ChargeStatus::PartiallyPaid);
+        assert_eq!(db.amount_due(&charge), dec("50"));
+
+        // 0.5 EUR short is within the tolerance
+        db.record_payment(&charge, payment("b", "0.0099")).unwrap();
+        assert_eq!(db.charge_status(&charge), ChargeStatus::Paid);
+        assert!(db.ledger().iter().all(|e| e.kind == LedgerKind::Payment));
+    }
+
+    #[test]
+    fn failed_save_changes_nothing() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        // The temporary file can't be created over a directory
+        fs::create_dir(scratch.path("db.tmp")).unwrap();
+        assert!(db.record_payment(&charge, payment("a", "0.02")).is_err());
+        assert!(db.payments_for(charge.id).is_empty());
+        assert!(db.ledger().is_empty());
+        assert_eq!(db.charge_status(&charge), ChargeStatus::Open);
+
+        fs::remove_dir(scratch.path("db.tmp")).unwrap();
+        db.record_payment(&charge, payment("a", "0.02")).unwrap();
+        assert_eq!(db.charge_status(&charge), ChargeStatus::Paid);
+    }
+
+    #[test]
+    fn pending_payments_only_count_once_recorded() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        db.record_pending_payment(payment("a", "0.02")).unwrap();
+        db.record_pending_payment(payment("a", "0.02")).unwrap();
+        assert_eq!(db.pending_payments_for(charge.id).len(), 1);
+        assert_eq!(db.charge_status(&charge), ChargeStatus::Open);
+
+        db.record_payment(&charge, payment("a", "0.02")).unwrap();
+        assert!(db.pending_payments_for(charge.id).is_empty());
+        assert_eq!(db.charge_status(&charge), ChargeStatus::Paid);
+    }
+
+    #[test]
+    fn overpayment_is_recorded() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        db.record_payment(&charge, payment("a", "0.03")).unwrap();
+        assert_eq!(db.charge_status(&charge), ChargeStatus::Paid);
+
+        let ledger = db.ledger();
+        assert_eq!(ledger.len(), 2);
+        assert_eq!(ledger[1].kind, LedgerKind::Overpayment);
+        assert_eq!(ledger[1].eur_amount, dec("50.00"));
+        assert_eq!(ledger[1].btc_amount, dec("0.01000000"));
+    }
+
+    #[test]
+    fn payment_for_cancelled_charge_is_refundable() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        db.cancel_charge(charge.id, CancellationKind::Cancel).unwrap();
+        let payment = db.record_payment(&charge, payment("a", "0.02")).unwrap();
+        assert!(payment.needs_review);
+
+        let ledger = db.ledger();
+        assert_eq!(ledger[1].kind, LedgerKind::Overpayment);
+        assert_eq!(ledger[1].eur_amount, dec("100.00"));
+    }
+
+    #[test]
+    fn payment_is_recorded_once() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        db.record_payment(&charge, payment("a", "0.01")).unwrap();
+        assert!(matches!(db.record_payment(&charge, payment("a", "0.01")), Err(Error::DuplicatePayment)));
+        assert_eq!(db.payments_for(charge.id).len(), 1);
+        assert_eq!(db.ledger().len(), 1);
+        assert_eq!(db.amount_due(&charge), dec("50"));
+    }
+
+    #[test]
+    fn charge_with_payments_cant_be_cancelled() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        db.record_payment(&charge, payment("a", "0.01")).unwrap();
+        assert!(matches!(db.cancel_charge(charge.id, CancellationKind::Void), Err(Error::HasPayments)));
+        assert_eq!(db.charge_status(&charge), ChargeStatus::PartiallyPaid);
+    }
+
+    #[test]
+    fn refund_lifecycle() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        db.record_payment(&charge, payment("a", "0.03")).unwrap();
+        assert_eq!(db.refundable_btc(charge.id), dec("0.01"));
+        assert_eq!(db.reserved_btc(), dec("0.01"));
+
+        let refund = db.request_refund(charge.id, "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy")
+            .unwrap().unwrap();
+        assert_eq!(db.refundable_btc(charge.id), dec("0"));
+        assert!(db.request_refund(charge.id, "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy")
+            .unwrap().is_none());
+
+        // Refunds can only be sent once approved
+        assert!(db.update_refund(refund.id, RefundStatus::Approved, RefundStatus::Sent)
+            .unwrap().is_none());
+        db.update_refund(refund.id, RefundStatus::Requested, RefundStatus::Approved)
+            .unwrap().unwrap();
+        db.complete_refund(refund.id, "X123", dec("5000")).unwrap();
+
+        assert_eq!(db.refunds()[0].status, RefundStatus::Sent);
+        assert_eq!(db.reserved_btc(), dec("0"));
+        assert_eq!(db.ledger().last().unwrap().kind, LedgerKind::Refund);
+    }
+
+    #[test]
+    fn failed_refunds_stay_reserved_until_rejected() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        db.record_payment(&charge, payment("a", "0.03")).unwrap();
+        let refund = db.request_refund(charge.id, "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy")
+            .unwrap().unwrap();
+        assert_eq!(db.reserved_btc(), dec("0.01"));
+        db.update_refund(refund.id, RefundStatus::Requested, RefundStatus::Rejected)
+            .unwrap().unwrap();
+        assert_eq!(db.reserved_btc(), dec("0"));
+
+        // Asking again reserves it again, and a failed send keeps it reserved
+        let refund = db.request_refund(charge.id, "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy")
+            .unwrap().unwrap();
+        db.update_refund(refund.id, RefundStatus::Requested, RefundStatus::Approved)
+            .unwrap().unwrap();
+        assert_eq!(db.reserved_btc(), dec("0.01"));
+        db.update_refund(refund.id, RefundStatus::Approved, RefundStatus::Failed)
+            .unwrap().unwrap();
+        assert_eq!(db.reserved_btc(), dec("0.01"));
+        assert_eq!(db.refundable_btc(charge.id), dec("0"));
+
+        // Until it is written off
+        db.update_refund(refund.id, RefundStatus::Failed, RefundStatus::Rejected)
+            .unwrap().unwrap();
+        assert_eq!(db.reserved_btc(), dec("0"));
+    }
+
+    #[test]
+    fn refund_with_unknown_outcome_stays_sending() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let charge = charge();
+
+        db.record_payment(&charge, payment("a", "0.03")).unwrap();
+        let refund = db.request_refund(charge.id, "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy")
+            .unwrap().unwrap();
+        db.update_refund(refund.id, RefundStatus::Requested, RefundStatus::Approved)
+            .unwrap().unwrap();
+        db.update_refund(refund.id, RefundStatus::Approved, RefundStatus::Sending)
+            .unwrap().unwrap();
+        db.fail_refund(refund.id, RefundStatus::Sending, "Connection failed").unwrap();
+
+        let refund = &db.refunds()[0];
+        assert_eq!(refund.status, RefundStatus::Sending);
+        assert_eq!(refund.failure.as_deref(), Some("Connection failed"));
+        assert_eq!(db.reserved_btc(), dec("0.01"));
+    }
+
+    #[test]
+    fn recurring_charge_is_issued_once() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let mut template = charge();
+        template.recurrence = Some(Recurrence{
+            template: "acme".to_string(),
+            period: NaiveDate::from_ymd_opt(2025, 1, 1).unwrap(),
+            notified: false,
+            emailed: false,
+        });
+        let addresses = vec![
+            "1Archive1n2C579dMsAu3iC6tWzuQJz8dN".to_string(),
+            "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy".to_string(),
+        ];
+
+        let issued = db.issue_charge(template.clone(), &addresses).unwrap().unwrap();
+        assert_eq!(issued.id, FIRST_ISSUED_CHARGE_ID);
+        // The first address belongs to the configured charge
+        assert_eq!(issued.btc_address, "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy");
+        assert!(db.issue_charge(template.clone(), &addresses).unwrap().is_none());
+
+        template.recurrence.as_mut().unwrap().period = NaiveDate::from_ymd_opt(2025, 2, 1).unwrap();
+        assert!(matches!(db.issue_charge(template, &addresses), Err(Error::NoFreeAddress)));
+
+        db.mark_notified(issued.id).unwrap();
+        let issued = db.get_charge_by_id(issued.id).unwrap();
+        assert!(issued.recurrence.unwrap().notified);
+        assert_eq!(db.charges().len(), 2);
+        assert_eq!(db.charges_for_customer(1).len(), 2);
+        assert_eq!(db.charges_for_customer(2).len(), 0);
+    }
+
+    #[test]
+    fn exchanges_survive_reopening() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let sell = db.record_exchange(ExchangeKind::Sell, 1250000, dec("0.0125")).unwrap();
+
+        // As if the process died waiting for the response
+        let db = open_db(&scratch);
+        let unfinished = db.unfinished_exchange().unwrap();
+        assert_eq!(unfinished.id, sell.id);
+        assert_eq!(unfinished.status, ExchangeStatus::Submitted);
+        assert_eq!(unfinished.submitted_at, sell.submitted_at);
+
+        db.confirm_exchange(sell.id, Some("360001"), Some(Sale{
+            trade_id: "360001".to_string(),
+            btc_amount: dec("0.0125"),
+            eur_amount: dec("500"),
+            btc_rate: dec("40000"),
+            sold_at: Utc::now(),
+        })).unwrap();
+        assert!(db.unfinished_exchange().is_none());
+        assert_eq!(db.sales().len(), 1);
+
+        let withdrawal = db.record_exchange(ExchangeKind::Withdrawal, 49910, dec("500")).unwrap();
+        assert_eq!(withdrawal.id, 2);
+        db.fail_exchange(withdrawal.id, "Insufficient funds").unwrap();
+        assert!(db.unfinished_exchange().is_none());
+        assert_eq!(db.exchanges()[1].failure.as_deref(), Some("Insufficient funds"));
+
+        let sell = db.record_exchange(ExchangeKind::Sell, 1000, dec("0.00001")).unwrap();
+        db.discard_exchange(sell.id).unwrap();
+        assert_eq!(db.exchanges().len(), 2);
+    }
+
+    #[test]
+    fn line_items_with_vat() {
+        let scratch = Scratch::new();
+        let db = open_db(&scratch);
+        let mut charge = charge();
+        charge.line_items = vec![
+            LineItem{description: "Hosting".to_string(), net_eur: dec("40.03"), vat_rate: dec("24")},
+            LineItem{description: "Support".to_string(), net_eur: dec("20"), vat_rate: dec("24")},
+            LineItem{description: "Book".to_string(), net_eur: dec("10"), vat_rate: dec("10")},
+        ];
+
+        // 40.03 * 24% = 9.6072 rounds to 9.61
+        assert_eq!(charge.line_items_total(), Some(dec("85.44")));
+        let breakdown = charge.vat_breakdown();
+        assert_eq!(breakdown.len(), 2);
+        assert_eq!(breakdown[0].net_eur, dec("60.03"));
+        assert_eq!(breakdown[0].vat_eur, dec("14.41"));
+        assert_eq!(breakdown[1].vat_eur, dec("1"));
+
+        charge.eur_amount = charge.line_items_total().unwrap();
+        db.record_payment(&charge, payment("a", "0.008544")).unwrap();
+        // Half of the charge is paid, so is half of the VAT
+        assert_eq!(db.ledger()[0].vat_eur, Some(dec("7.71")));
+        assert_eq!(round_cents(dec("-0.125")), dec("-0.13"));
+    }
+}
@@ -9,14 +9,50 @@ enum BigDecimalValue {
     Number(f64),
 }
 
+impl BigDecimalValue {
+    fn into_big_decimal<E>(self) -> Result<BigDecimal, E>
+        where E: de::Error
+    {
+        match self {
+            BigDecimalValue::String(s) => BigDecimal::from_str(s.as_str())
+                .map_err(de::Error::custom),
+            BigDecimalValue::Number(n) => BigDecimal::from_f64(n)
+                .ok_or(de::Error::custom("Unable to represent f64 as BigDecimal"))
+        }
+    }
+}
+
 pub fn deserialize_big_decimal<'de, D>(deserializer: D) -> Result<BigDecimal, D::Error>
     where D: Deserializer<'de>
 {
     let v: BigDecimalValue = Deserialize::deserialize(deserializer)?;
+    v.into_big_decimal()
+}
+
+/// Ids that are given as strings by some endpoints and as numbers by others
+pub fn deserialize_id<'de, D>(deserializer: D) -> Result<String, D::Error>
+    where D: Deserializer<'de>
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Id {
+        String(String),
+        Number(u64),
+    }
+
+    let v: Id = Deserialize::deserialize(deserializer)?;
+    match v {
+        Id::String(s) => Ok(s),
+        Id::Number(n) => Ok(n.to_string()),
+    }
+}
+
+pub fn deserialize_opt_big_decimal<'de, D>(deserializer: D) -> Result<Option<BigDecimal>, D::Error>
+    where D: Deserializer<'de>
+{
+    let v: Option<BigDecimalValue> = Deserialize::deserialize(deserializer)?;
     match v {
-        BigDecimalValue::String(s) => BigDecimal::from_str(s.as_str())
-            .map_err(de::Error::custom),
-        BigDecimalValue::Number(n) => BigDecimal::from_f64(n)
-            .ok_or(de::Error::custom("Unable to represent f64 as BigDecimal"))
+        Some(v) => v.into_big_decimal().map(Some),
+        None => Ok(None),
     }
 }
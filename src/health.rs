@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use hyper::{Response, Body, StatusCode};
+use gotham::helpers::http::response::create_response;
+use gotham::state::{FromState, State};
+
+use crate::admin;
+use crate::metrics;
+use crate::middleware::Env;
+use crate::rates::Disagreement;
+use crate::retry::CircuitState;
+use crate::worker::JobStats;
+
+/// Rates older than this aren't fresh enough to serve traffic with
+const READY_MAX_RATES_AGE_SECS: u64 = 5 * 60;
+/// The worker's scheduler looks for due jobs every second
+const READY_MAX_WORKER_SILENCE_SECS: u64 = 30;
+
+#[derive(Serialize, Debug)]
+struct Health {
+    /// `degraded` while Coinmotion is failing or no quotes can be made
+    status: &'static str,
+    coinmotion_circuit: CircuitState,
+    rates_trusted: bool,
+    /// The last time the rate sources disagreed, kept after they agree again
+    rate_disagreement: Option<Disagreement>,
+    /// Runs of the worker jobs, by job name
+    jobs: BTreeMap<&'static str, JobStats>,
+}
+
+/// Answers 200 whenever the process is up, so that it doubles as the liveness probe,
+/// also served at /healthz
+pub fn get_health(state: State) -> (State, Response<Body>) {
+    let res = {
+        let env = Env::borrow_from(&state);
+        let coinmotion_circuit = env.caches.coinmotion_circuit().state();
+        let rates_trusted = *env.caches.rates_trusted().read().unwrap();
+        let health = Health{
+            status: if coinmotion_circuit == CircuitState::Closed && rates_trusted {
+                "ok"
+            } else {
+                "degraded"
+            },
+            coinmotion_circuit,
+            rates_trusted,
+            rate_disagreement: env.caches.rate_disagreement().read().unwrap().clone(),
+            jobs: env.caches.jobs().read().unwrap().clone(),
+        };
+
+        create_response(
+            &state,
+            StatusCode::OK,
+            mime::APPLICATION_JSON,
+            serde_json::to_string(&health).unwrap().into_bytes(),
+        )
+    };
+
+    (state, res)
+}
+
+#[derive(Serialize, Debug)]
+struct Readiness {
+    ready: bool,
+    rates_fresh: bool,
+    worker_alive: bool,
+}
+
+/// Ready while the rates are fresh and the worker keeps running its jobs
+pub fn get_readyz(state: State) -> (State, Response<Body>) {
+    let res = {
+        let env = Env::borrow_from(&state);
+        let rates_fresh = env.caches.rates().read().unwrap().age()
+            .is_some_and(|age| age <= Duration::from_secs(READY_MAX_RATES_AGE_SECS));
+        let worker_alive = env.caches.worker_heartbeat().read().unwrap()
+            .and_then(|time| time.elapsed().ok())
+            .is_some_and(|silence| silence <= Duration::from_secs(READY_MAX_WORKER_SILENCE_SECS));
+        let readiness = Readiness{
+            ready: rates_fresh && worker_alive,
+            rates_fresh,
+            worker_alive,
+        };
+
+        create_response(
+            &state,
+            if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE },
+            mime::APPLICATION_JSON,
+            serde_json::to_string(&readiness).unwrap().into_bytes(),
+        )
+    };
+
+    (state, res)
+}
+
+/// The metrics include balances and sales, so they are behind the admin token
+pub fn get_metrics(state: State) -> (State, Response<Body>) {
+    if !admin::is_authorized(&state) {
+        let res = admin::unauthorized(&state);
+        return (state, res);
+    }
+
+    let res = {
+        let env = Env::borrow_from(&state);
+        create_response(
+            &state,
+            StatusCode::OK,
+            metrics::CONTENT_TYPE.parse::<mime::Mime>().unwrap(),
+            metrics::render(&env.caches, &env.db),
+        )
+    };
+
+    (state, res)
+}
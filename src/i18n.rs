@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    En,
+    Et,
+    Fi,
+    De,
+}
+
+pub const LANGUAGES: [Language; 4] = [Language::En, Language::Et, Language::Fi, Language::De];
+
+impl Language {
+    pub fn from_code(code: &str) -> Option<Self> {
+        // Only the primary subtag matters, "et-EE" is handled as "et"
+        let primary = code.split(['-', '_']).next().unwrap_or("");
+        match primary.trim().to_lowercase().as_str() {
+            "en" => Some(Language::En),
+            "et" => Some(Language::Et),
+            "fi" => Some(Language::Fi),
+            "de" => Some(Language::De),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Language::En => "en",
+            Language::Et => "et",
+            Language::Fi => "fi",
+            Language::De => "de",
+        }
+    }
+
+    fn catalog_source(&self) -> &'static str {
+        match *self {
+            Language::En => include_str!("../locales/en.toml"),
+            Language::Et => include_str!("../locales/et.toml"),
+            Language::Fi => include_str!("../locales/fi.toml"),
+            Language::De => include_str!("../locales/de.toml"),
+        }
+    }
+
+    /// Separators for the integer part groups and the decimal part
+    fn separators(&self) -> (&'static str, &'static str) {
+        match *self {
+            Language::En => (",", "."),
+            Language::Et | Language::Fi => ("\u{a0}", ","),
+            Language::De => (".", ","),
+        }
+    }
+}
+
+/// Translated strings of the payment page
+#[derive(Debug, Deserialize)]
+pub struct Messages {
+    pub paid: String,
+    pub paid_text: String,
+    pub download_receipt: String,
+    pub charge_closed: String,
+    pub charge_closed_text: String,
+    pub quote_unavailable: String,
+    pub quote_unavailable_text: String,
+    pub lock_in_expired: String,
+    pub lock_in_expired_text: String,
+    pub refresh_to_retry: String,
+    pub amount_locked_in_for: String,
+    pub transfer_amount: String,
+    pub transfer_remaining: String,
+    pub partially_paid: String,
+    pub to_address: String,
+    pub refund: String,
+    pub refund_text: String,
+    pub refund_address: String,
+    pub refund_submit: String,
+    pub refund_invalid: String,
+    pub refund_pending: String,
+    pub outstanding_invoices: String,
+    pub nothing_outstanding: String,
+    pub paid_invoices: String,
+    pub due_date: String,
+    pub pay: String,
+    pub receipt: String,
+    pub vat: String,
+    pub total: String,
+    pub qr_code_alt: String,
+    pub vat_number: String,
+    pub contact: String,
+    pub powered_by: String,
+    pub minute: String,
+    pub minutes: String,
+    pub second: String,
+    pub seconds: String,
+}
+
+pub struct Catalogs {
+    messages: HashMap<Language, Messages>,
+}
+
+impl Catalogs {
+    pub fn load() -> Self {
+        let messages = LANGUAGES.iter()
+            .map(|lang| {
+                let messages = toml::from_str(lang.catalog_source())
+                    .expect("translation catalog isn't valid");
+                (*lang, messages)
+            })
+            .collect();
+
+        Self{
+            messages,
+        }
+    }
+
+    pub fn get(&self, lang: Language) -> &Messages {
+        &self.messages[&lang]
+    }
+}
+
+/// Pick the page language, in the order of preference: explicit `?lang=` query,
+/// the browser `Accept-Language` and finally the charge default language.
+pub fn negotiate(query: Option<&str>, accept_language: Option<&str>, charge_default: Option<&str>) -> Language {
+    query.and_then(Language::from_code)
+        .or_else(|| accept_language.and_then(from_accept_language))
+        .or_else(|| charge_default.and_then(Language::from_code))
+        .unwrap_or(Language::En)
+}
+
+fn from_accept_language(header: &str) -> Option<Language> {
+    let mut ranges: Vec<(Language, f32)> = header.split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let lang = Language::from_code(parts.next()?)?;
+            let q = parts
+                .filter_map(|p| p.trim().strip_prefix("q=")?.parse().ok())
+                .next()
+                .unwrap_or(1.0);
+            Some((lang, q))
+        })
+        .filter(|&(_, q)| q > 0.0)
+        .collect();
+
+    // Stable sort keeps the header order for equal weights
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    ranges.first().map(|&(lang, _)| lang)
+}
+
+/// Format a plain decimal number (as printed by `BigDecimal`) for the language
+pub fn format_decimal(value: &str, lang: Language) -> String {
+    let (group_sep, decimal_sep) = lang.separators();
+    let (sign, value) = match value.strip_prefix('-') {
+        Some(value) => ("-", value),
+        None => ("", value),
+    };
+    let (int_part, frac_part) = match value.find('.') {
+        Some(idx) => (&value[..idx], Some(&value[idx + 1..])),
+        None => (value, None),
+    };
+
+    let mut out = String::from(sign);
+    for (i, c) in int_part.chars().enumerate() {
+        if i > 0 && (int_part.len() - i) % 3 == 0 {
+            out.push_str(group_sep);
+        }
+        out.push(c);
+    }
+    if let Some(frac_part) = frac_part {
+        out.push_str(decimal_sep);
+        out.push_str(frac_part);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalogs_are_complete() {
+        let catalogs = Catalogs::load();
+        for lang in LANGUAGES.iter() {
+            assert!(!catalogs.get(*lang).paid.is_empty());
+        }
+    }
+
+    #[test]
+    fn negotiate_language() {
+        assert_eq!(negotiate(Some("de"), Some("fi"), Some("et")), Language::De);
+        assert_eq!(negotiate(Some("xx"), Some("fi"), Some("et")), Language::Fi);
+        assert_eq!(negotiate(None, Some("fi-FI,fi;q=0.9,en;q=0.8"), None), Language::Fi);
+        assert_eq!(negotiate(None, Some("ru,en;q=0.5,de;q=0.7"), None), Language::De);
+        assert_eq!(negotiate(None, Some("et;q=0,fi"), None), Language::Fi);
+        assert_eq!(negotiate(None, None, None), Language::En);
+    }
+
+    #[test]
+    fn accept_language_wins_over_charge_default() {
+        assert_eq!(negotiate(None, Some("de-DE,de;q=0.9"), Some("et")), Language::De);
+        // Only used when the browser asks for nothing we have
+        assert_eq!(negotiate(None, Some("ru,sv;q=0.8"), Some("et")), Language::Et);
+        assert_eq!(negotiate(None, None, Some("et")), Language::Et);
+    }
+
+    #[test]
+    fn format_decimals() {
+        assert_eq!(format_decimal("1234567.89", Language::En), "1,234,567.89");
+        assert_eq!(format_decimal("1234.5", Language::Et), "1\u{a0}234,5");
+        assert_eq!(format_decimal("1234.5", Language::De), "1.234,5");
+        assert_eq!(format_decimal("0.0123", Language::Fi), "0,0123");
+        assert_eq!(format_decimal("-100", Language::En), "-100");
+    }
+}
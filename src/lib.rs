@@ -0,0 +1,71 @@
+//! BitCharge serves payment pages for invoices that are paid in bitcoin and
+//! exchanges the received BTC to EUR on Coinmotion.
+//!
+//! Besides the server, the crate exposes the parts that are useful on their own:
+//!
+//! - [`coinmotion`](coinmotion/index.html) is a client for the Coinmotion v1 API, with
+//!   request signing, retries and typed errors
+//! - [`quote`](quote/index.html) and [`pricing`](pricing/index.html) turn an EUR amount
+//!   into the BTC amount quoted to a payer
+//! - [`Charge`](struct.Charge.html) is the charge model the server stores
+//!
+//! The server itself is behind the default `server` feature. Build with
+//! `default-features = false` to leave it and its web dependencies out.
+//!
+//! ```no_run
+//! use std::str::FromStr;
+//! use bigdecimal::BigDecimal;
+//! use bitcharge::pricing::PricingConfig;
+//! use bitcharge::Quote;
+//!
+//! let eur_amount = BigDecimal::from_str("120").unwrap();
+//! let btc_bid = BigDecimal::from_str("40000").unwrap();
+//! let quote = Quote::new(eur_amount, btc_bid, &PricingConfig::default());
+//! println!("Pay {} BTC", quote.btc_amount);
+//! ```
+
+#[macro_use] extern crate serde_derive;
+#[cfg(feature = "server")]
+#[macro_use] extern crate gotham_derive;
+#[macro_use] extern crate log;
+
+mod de;
+mod ser;
+pub mod quote;
+pub mod coinmotion;
+// Only the charge model is public, the store and its bookkeeping are there for the server
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+mod db;
+pub mod pricing;
+pub mod retry;
+pub mod nonce;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(test)]
+mod test_util;
+
+#[cfg(feature = "server")] mod bip21;
+#[cfg(feature = "server")] mod bitcoin;
+#[cfg(feature = "server")] mod conf;
+#[cfg(feature = "server")] mod cache;
+#[cfg(feature = "server")] mod worker;
+#[cfg(feature = "server")] mod middleware;
+#[cfg(feature = "server")] mod web;
+#[cfg(feature = "server")] mod payment_request;
+#[cfg(feature = "server")] mod admin;
+#[cfg(feature = "server")] mod pdf;
+#[cfg(feature = "server")] mod receipt;
+#[cfg(feature = "server")] mod i18n;
+#[cfg(feature = "server")] mod refund;
+#[cfg(feature = "server")] mod recurring;
+#[cfg(feature = "server")] mod customer;
+#[cfg(feature = "server")] mod rates;
+#[cfg(feature = "server")] mod rate_history;
+#[cfg(feature = "server")] mod health;
+#[cfg(feature = "server")] mod metrics;
+#[cfg(feature = "server")] mod logging;
+#[cfg(feature = "server")] mod alerts;
+
+pub use coinmotion::Coinmotion;
+pub use db::{Charge, LineItem, Recurrence};
+pub use quote::Quote;
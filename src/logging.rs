@@ -0,0 +1,198 @@
+//! Log output, either as plain lines for people or as JSON lines for a log store.
+//! Fields given to the log macros, e.g. `info!(charge_id = charge.id; "...")`, are
+//! written out with the message, along with the span ID of the HTTP request or job
+//! run the message was logged in.
+
+use std::future::Future;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{SecondsFormat, Utc};
+use env_logger::filter::{self, Filter};
+use log::{Log, Metadata, Record};
+use log::kv::{self, Key, Value, VisitSource};
+use serde_json::{Map, Number};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+tokio::task_local! {
+    static SPAN_ID: String;
+}
+
+static NEXT_SPAN: AtomicU64 = AtomicU64::new(1);
+
+/// Log to stderr in `format`, filtered by `RUST_LOG` as before
+pub fn init(format: LogFormat) {
+    let filter = filter::Builder::from_env("RUST_LOG").build();
+    log::set_max_level(filter.filter());
+    log::set_boxed_logger(Box::new(Logger{filter, format}))
+        .expect("logger was already set up");
+}
+
+/// New span ID for a run of `name`, e.g. `exchange-12`
+pub fn new_span_id(name: &str) -> String {
+    format!("{}-{}", name, NEXT_SPAN.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Messages logged while `f` runs carry `span_id`
+pub async fn in_span<F: Future>(span_id: String, f: F) -> F::Output {
+    SPAN_ID.scope(span_id, f).await
+}
+
+/// Messages logged while `f` runs carry `span_id`, for code that doesn't await
+pub fn in_span_sync<R, F: FnOnce() -> R>(span_id: String, f: F) -> R {
+    SPAN_ID.sync_scope(span_id, f)
+}
+
+fn span_id() -> Option<String> {
+    SPAN_ID.try_with(|id| id.clone()).ok()
+}
+
+struct Logger {
+    filter: Filter,
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Human => human_line(record, span_id()),
+            LogFormat::Json => json_line(record, span_id()),
+        };
+        // Nowhere left to report a failed write to
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+fn human_line(record: &Record, span_id: Option<String>) -> String {
+    let mut line = format!(
+        "{} {:<5} {} > {}",
+        Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+        record.level(),
+        record.target(),
+        record.args(),
+    );
+    let mut fields = HumanFields(&mut line);
+    let _ = record.key_values().visit(&mut fields);
+    if let Some(span_id) = span_id {
+        line.push_str(&format!(" span_id={}", span_id));
+    }
+    line
+}
+
+struct HumanFields<'a>(&'a mut String);
+
+impl<'a, 'kvs> VisitSource<'kvs> for HumanFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push_str(&format!(" {}={}", key, value));
+        Ok(())
+    }
+}
+
+fn json_line(record: &Record, span_id: Option<String>) -> String {
+    let mut line = Map::new();
+    line.insert("timestamp".into(), Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true).into());
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    line.insert("message".into(), record.args().to_string().into());
+    if let Some(span_id) = span_id {
+        line.insert("span_id".into(), span_id.into());
+    }
+    let mut fields = JsonFields(&mut line);
+    let _ = record.key_values().visit(&mut fields);
+    serde_json::Value::Object(line).to_string()
+}
+
+struct JsonFields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'a, 'kvs> VisitSource<'kvs> for JsonFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), json_value(&value));
+        Ok(())
+    }
+}
+
+/// Numbers and booleans stay as they are, everything else is written out as text
+fn json_value(value: &Value) -> serde_json::Value {
+    if let Some(b) = value.to_bool() {
+        b.into()
+    } else if let Some(n) = value.to_u64() {
+        n.into()
+    } else if let Some(n) = value.to_i64() {
+        n.into()
+    } else if let Some(n) = value.to_f64().and_then(Number::from_f64) {
+        serde_json::Value::Number(n)
+    } else {
+        value.to_string().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn json_lines_carry_fields() {
+        let kvs: &[(&str, Value)] = &[
+            ("charge_id", Value::from(42u64)),
+            ("hashid", Value::from("aBc123")),
+            ("late", Value::from(true)),
+        ];
+        let line = json_line(
+            &Record::builder()
+                .args(format_args!("Received payment"))
+                .level(Level::Info)
+                .target("bitcharge::payment_request")
+                .key_values(&kvs)
+                .build(),
+            Some("9f2d3c1e".to_string()),
+        );
+
+        let line: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(line["message"], "Received payment");
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["span_id"], "9f2d3c1e");
+        assert_eq!(line["charge_id"], 42);
+        assert_eq!(line["hashid"], "aBc123");
+        assert_eq!(line["late"], true);
+    }
+
+    #[test]
+    fn human_lines_end_with_fields() {
+        let kvs: &[(&str, Value)] = &[("trade_id", Value::from("360001"))];
+        let line = human_line(
+            &Record::builder()
+                .args(format_args!("Sold BTC"))
+                .level(Level::Info)
+                .target("bitcharge::worker")
+                .key_values(&kvs)
+                .build(),
+            Some("exchange-3".to_string()),
+        );
+        assert!(line.ends_with("INFO  bitcharge::worker > Sold BTC trade_id=360001 span_id=exchange-3"), "{}", line);
+    }
+
+    #[tokio::test]
+    async fn span_ids_follow_the_future() {
+        assert_eq!(span_id(), None);
+        let id = in_span("exchange-1".to_string(), async { span_id() }).await;
+        assert_eq!(id.as_deref(), Some("exchange-1"));
+        assert_eq!(in_span_sync("9f2d3c1e".to_string(), span_id).as_deref(), Some("9f2d3c1e"));
+    }
+}
@@ -0,0 +1,191 @@
+//! Metrics in the Prometheus text exposition format
+
+use std::fmt::{Display, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use bigdecimal::{BigDecimal, Zero};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::cache::Caches;
+use crate::db::{Database, ExchangeKind, ExchangeStatus};
+use crate::retry::CircuitState;
+
+/// Content type of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub fn render(caches: &Caches, db: &Database) -> String {
+    let mut out = Exposition::new();
+
+    if let Some(age) = caches.rates().read().unwrap().age() {
+        out.family("bitcharge_rates_age_seconds", "gauge", "Seconds since the rates were last updated");
+        out.sample("bitcharge_rates_age_seconds", &[], age.as_secs_f64());
+    }
+    out.family("bitcharge_rates_trusted", "gauge", "Whether the rate sources agree and quotes are made");
+    out.sample("bitcharge_rates_trusted", &[], *caches.rates_trusted().read().unwrap() as u8);
+    if let Some(ref disagreement) = *caches.rate_disagreement().read().unwrap() {
+        out.family("bitcharge_rates_last_deviation_percent", "gauge", "How far off the closest rate source was when the sources last disagreed");
+        out.sample("bitcharge_rates_last_deviation_percent", &[("source", &disagreement.source)], &disagreement.deviation_percent);
+    }
+
+    let circuit = caches.coinmotion_circuit().state();
+    out.family("bitcharge_coinmotion_circuit_state", "gauge", "State of the Coinmotion circuit breaker, 1 for the current state");
+    for state in &[CircuitState::Closed, CircuitState::Open, CircuitState::HalfOpen] {
+        out.sample("bitcharge_coinmotion_circuit_state", &[("state", &label(state))], (circuit == *state) as u8);
+    }
+
+    let endpoints = caches.coinmotion_calls().endpoints();
+    out.family("bitcharge_coinmotion_last_success_timestamp_seconds", "gauge", "Last successful Coinmotion call, by endpoint");
+    for (endpoint, stats) in &endpoints {
+        if let Some(time) = stats.last_success {
+            out.sample("bitcharge_coinmotion_last_success_timestamp_seconds", &[("endpoint", endpoint)], unix_time(time));
+        }
+    }
+    out.family("bitcharge_coinmotion_errors_total", "counter", "Failed Coinmotion calls, by endpoint and kind of error");
+    for (endpoint, stats) in &endpoints {
+        for (error, count) in &stats.errors {
+            out.sample("bitcharge_coinmotion_errors_total", &[("endpoint", endpoint), ("error", error)], count);
+        }
+    }
+
+    if let Some(ref bal) = *caches.balances().read().unwrap() {
+        out.family("bitcharge_balance", "gauge", "Coinmotion balances as last fetched");
+        let balances = [
+            ("btc", "total", &bal.btc_bal), ("btc", "available", &bal.btc_avl), ("btc", "reserved", &bal.btc_res),
+            ("eur", "total", &bal.eur_bal), ("eur", "available", &bal.eur_avl), ("eur", "reserved", &bal.eur_res),
+        ];
+        for &(currency, kind, amount) in balances.iter() {
+            out.sample("bitcharge_balance", &[("currency", currency), ("kind", kind)], amount);
+        }
+    }
+
+    let sales = db.sales();
+    out.family("bitcharge_sales_total", "counter", "BTC sells made");
+    out.sample("bitcharge_sales_total", &[], sales.len());
+    out.family("bitcharge_sold_btc_total", "counter", "BTC sold");
+    out.sample("bitcharge_sold_btc_total", &[], sales.iter().fold(BigDecimal::zero(), |acc, s| acc + s.btc_amount.clone()));
+    out.family("bitcharge_sold_eur_total", "counter", "EUR received for the BTC sold");
+    out.sample("bitcharge_sold_eur_total", &[], sales.iter().fold(BigDecimal::zero(), |acc, s| acc + s.eur_amount.clone()));
+
+    let exchanges = db.exchanges();
+    let withdrawn = exchanges.iter()
+        .filter(|e| e.kind == ExchangeKind::Withdrawal && e.status == ExchangeStatus::Confirmed);
+    out.family("bitcharge_withdrawals_total", "counter", "EUR withdrawals made");
+    out.sample("bitcharge_withdrawals_total", &[], withdrawn.clone().count());
+    out.family("bitcharge_withdrawn_eur_total", "counter", "EUR withdrawn");
+    let withdrawn_cents = withdrawn.map(|e| e.amount).sum::<u64>();
+    out.sample("bitcharge_withdrawn_eur_total", &[], (BigDecimal::from(withdrawn_cents) / BigDecimal::from(100)).with_scale(2));
+    out.family("bitcharge_exchange_actions", "gauge", "Sells and withdrawals on record, by kind and status");
+    for kind in &[ExchangeKind::Sell, ExchangeKind::Withdrawal] {
+        for status in &[ExchangeStatus::Submitted, ExchangeStatus::Confirmed, ExchangeStatus::Failed] {
+            let count = exchanges.iter().filter(|e| e.kind == *kind && e.status == *status).count();
+            out.sample("bitcharge_exchange_actions", &[("kind", &label(kind)), ("status", &label(status))], count);
+        }
+    }
+
+    out.family("bitcharge_pay_page_hits_total", "counter", "Views of the pay page, by charge ID");
+    for (charge_id, hits) in caches.pay_page_hits().read().unwrap().iter() {
+        out.sample("bitcharge_pay_page_hits_total", &[("charge_id", &charge_id.to_string())], hits);
+    }
+
+    let jobs = caches.jobs().read().unwrap().clone();
+    out.family("bitcharge_job_runs_total", "counter", "Runs of the worker jobs, counted as they start");
+    for (job, stats) in &jobs {
+        out.sample("bitcharge_job_runs_total", &[("job", job)], stats.runs);
+    }
+    out.family("bitcharge_job_failures_total", "counter", "Runs of the worker jobs that failed or timed out");
+    for (job, stats) in &jobs {
+        out.sample("bitcharge_job_failures_total", &[("job", job)], stats.failures);
+    }
+    out.family("bitcharge_job_skipped_total", "counter", "Runs of the worker jobs skipped while the job was still running");
+    for (job, stats) in &jobs {
+        out.sample("bitcharge_job_skipped_total", &[("job", job)], stats.skipped);
+    }
+    out.family("bitcharge_job_last_success_timestamp_seconds", "gauge", "Last successful run of the worker jobs");
+    for (job, stats) in &jobs {
+        if let Some(time) = stats.last_success {
+            out.sample("bitcharge_job_last_success_timestamp_seconds", &[("job", job)], timestamp(time));
+        }
+    }
+
+    out.finish()
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+fn timestamp(time: DateTime<Utc>) -> f64 {
+    time.timestamp_millis() as f64 / 1000.0
+}
+
+/// The name the value is serialized with
+fn label<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value).ok()
+        .and_then(|v| v.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+/// Writes metric families one after the other, every sample of a family has to
+/// be written before the next family is started
+struct Exposition {
+    buf: String,
+}
+
+impl Exposition {
+    fn new() -> Self {
+        Self{buf: String::new()}
+    }
+
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.buf, "# HELP {} {}", name, help).unwrap();
+        writeln!(self.buf, "# TYPE {} {}", name, kind).unwrap();
+    }
+
+    fn sample<V: Display>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+        self.buf.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<_> = labels.iter()
+                .map(|&(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+                .collect();
+            write!(self.buf, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(self.buf, " {}", value).unwrap();
+    }
+
+    fn finish(self) -> String {
+        self.buf
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposition_format() {
+        let mut out = Exposition::new();
+        out.family("bitcharge_errors_total", "counter", "Errors");
+        out.sample("bitcharge_errors_total", &[], 3);
+        out.sample("bitcharge_errors_total", &[("endpoint", "/sell"), ("error", "say \"hi\"\\\n")], 1);
+
+        assert_eq!(out.finish(), concat!(
+            "# HELP bitcharge_errors_total Errors\n",
+            "# TYPE bitcharge_errors_total counter\n",
+            "bitcharge_errors_total 3\n",
+            "bitcharge_errors_total{endpoint=\"/sell\",error=\"say \\\"hi\\\"\\\\\\n\"} 1\n",
+        ));
+    }
+
+    #[test]
+    fn labels_are_serialized_names() {
+        assert_eq!(label(&ExchangeKind::Withdrawal), "withdrawal");
+        assert_eq!(label(&ExchangeStatus::Submitted), "submitted");
+        assert_eq!(label(&CircuitState::HalfOpen), "half_open");
+    }
+}
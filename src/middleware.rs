@@ -1,37 +1,104 @@
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Instant;
+use futures::future::FutureExt;
 use gotham::handler::HandlerFuture;
 use gotham::middleware::Middleware;
-use gotham::state::State;
+use gotham::state::{request_id, FromState, State};
+use hyper::{Method, Uri};
 
-use cache::Caches;
-use db::Database;
+use crate::cache::Caches;
+use crate::conf::BrandingConfig;
+use crate::db::Database;
+use crate::i18n::Catalogs;
+use crate::logging;
+use crate::pricing::PricingConfig;
+use crate::rate_history::RateHistory;
 use harsh::Harsh;
+use secp256k1::SecretKey;
+use url::Url;
 
 #[derive(StateData)]
 pub struct Env {
     pub db: Arc<Database>,
     pub caches: Arc<Caches>,
+    pub rate_history: Arc<RateHistory>,
     pub hashids: Harsh,
+    pub company_name: Option<String>,
+    pub payment_request_key: Option<SecretKey>,
+    pub base_url: Url,
+    pub admin_token: Option<String>,
+    pub catalogs: Arc<Catalogs>,
+    pub branding: Arc<BrandingConfig>,
+    pub pricing: Arc<PricingConfig>,
 }
 
 #[derive(Clone, NewMiddleware)]
 pub struct EnvMiddleware {
     pub db: Arc<Database>,
     pub caches: Arc<Caches>,
+    pub rate_history: Arc<RateHistory>,
     pub hashids: Harsh,
+    pub company_name: Option<String>,
+    pub payment_request_key: Option<SecretKey>,
+    pub base_url: Url,
+    pub admin_token: Option<String>,
+    pub catalogs: Arc<Catalogs>,
+    pub branding: Arc<BrandingConfig>,
+    pub pricing: Arc<PricingConfig>,
 }
 
 impl Middleware for EnvMiddleware {
-    fn call<Chain>(self, mut state: State, chain: Chain) -> Box<HandlerFuture>
-        where Chain: FnOnce(State) -> Box<HandlerFuture>,
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+        where Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
     {
         state.put(Env{
             db: self.db,
             caches: self.caches,
+            rate_history: self.rate_history,
             hashids: self.hashids,
+            company_name: self.company_name,
+            payment_request_key: self.payment_request_key,
+            base_url: self.base_url,
+            admin_token: self.admin_token,
+            catalogs: self.catalogs,
+            branding: self.branding,
+            pricing: self.pricing,
         });
 
         chain(state)
     }
 }
 
+
+/// Logs every request, and gives the messages logged while it is handled
+/// the request ID as their span ID
+#[derive(Clone, NewMiddleware)]
+pub struct SpanMiddleware;
+
+impl Middleware for SpanMiddleware {
+    fn call<Chain>(self, state: State, chain: Chain) -> Pin<Box<HandlerFuture>>
+        where Chain: FnOnce(State) -> Pin<Box<HandlerFuture>> + Send + 'static,
+    {
+        let span_id = request_id(&state).to_string();
+        let method = Method::borrow_from(&state).clone();
+        let path = Uri::borrow_from(&state).path().to_string();
+        let started = Instant::now();
+
+        // Handlers that don't await run right away, inside the chain call
+        let handled = logging::in_span_sync(span_id.clone(), || chain(state));
+        logging::in_span(span_id, async move {
+            let res = handled.await;
+            let status = match res {
+                Ok((_, ref response)) => response.status(),
+                Err((_, ref err)) => err.status(),
+            };
+            info!(
+                method = method.as_str(), path = path.as_str(), status = status.as_u16(),
+                duration_ms = started.elapsed().as_millis() as u64;
+                "{} {} {}", method, path, status.as_u16()
+            );
+            res
+        }).boxed()
+    }
+}
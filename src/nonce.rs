@@ -0,0 +1,136 @@
+//! Nonces for signed API requests.
+
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Nonces handed out before the high-water mark on disk has to be moved again
+const RESERVE: u64 = 1000;
+/// How far to skip ahead when Coinmotion rejects a nonce, 10 minutes worth of centiseconds
+const RECOVERY_JUMP: u64 = 60_000;
+
+/// Strictly increasing nonces for signed requests. They follow the wall clock in
+/// centiseconds, but never go backwards when the clock does or when several requests
+/// are made within the same centisecond. A high-water mark is kept on disk so that
+/// nonces used before a restart aren't handed out again.
+pub struct Nonces {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+struct State {
+    last: u64,
+    /// Every nonce up to this has possibly been used
+    reserved: u64,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    ParseError(String),
+}
+
+impl Nonces {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let path = PathBuf::from(path);
+        let reserved = match File::open(&path) {
+            Ok(mut f) => {
+                let mut buf = String::new();
+                f.read_to_string(&mut buf).map_err(Error::IoError)?;
+                buf.trim().parse::<u64>()
+                    .map_err(|_| Error::ParseError(buf.clone()))?
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(Error::IoError(err)),
+        };
+
+        Ok(Self{
+            path,
+            state: Mutex::new(State{
+                last: reserved,
+                reserved,
+            }),
+        })
+    }
+
+    pub fn next(&self) -> Result<u64, Error> {
+        let mut state = self.state.lock().unwrap();
+        let nonce = cmp::max(state.last + 1, clock());
+        self.use_nonce(&mut state, nonce)
+    }
+
+    /// Skip well ahead after Coinmotion has rejected a nonce, in case it has seen
+    /// higher nonces than we know of (e.g. the same API key is used elsewhere)
+    pub fn recover(&self) -> Result<u64, Error> {
+        let mut state = self.state.lock().unwrap();
+        let nonce = cmp::max(state.last, clock()) + RECOVERY_JUMP;
+        warn!("Skipping nonces ahead to {}", nonce);
+        self.use_nonce(&mut state, nonce)
+    }
+
+    fn use_nonce(&self, state: &mut State, nonce: u64) -> Result<u64, Error> {
+        if nonce > state.reserved {
+            let reserved = nonce + RESERVE;
+            self.save(reserved)?;
+            state.reserved = reserved;
+        }
+        state.last = nonce;
+        Ok(nonce)
+    }
+
+    fn save(&self, reserved: u64) -> Result<(), Error> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut f = File::create(&tmp_path).map_err(Error::IoError)?;
+            f.write_all(reserved.to_string().as_bytes()).map_err(Error::IoError)?;
+            f.sync_all().map_err(Error::IoError)?;
+        }
+        fs::rename(&tmp_path, &self.path).map_err(Error::IoError)
+    }
+}
+
+fn clock() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    now.as_secs() * 100 + now.subsec_nanos() as u64 / 10_000_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Scratch;
+
+    #[test]
+    fn nonces_increase_across_restarts() {
+        let scratch = Scratch::new();
+        let path = scratch.path("nonce");
+        let nonces = Nonces::open(&path).unwrap();
+        let mut last = 0;
+        for _ in 0..2500 {
+            let nonce = nonces.next().unwrap();
+            assert!(nonce > last);
+            last = nonce;
+        }
+        assert!(last >= clock() - 100);
+
+        let nonces = Nonces::open(&path).unwrap();
+        assert!(nonces.next().unwrap() > last);
+    }
+
+    #[test]
+    fn recovery_skips_ahead() {
+        let scratch = Scratch::new();
+        let path = scratch.path("nonce");
+        let nonces = Nonces::open(&path).unwrap();
+
+        let nonce = nonces.next().unwrap();
+        let recovered = nonces.recover().unwrap();
+        assert!(recovered >= nonce + RECOVERY_JUMP);
+        assert!(nonces.next().unwrap() > recovered);
+
+        let nonces = Nonces::open(&path).unwrap();
+        assert!(nonces.next().unwrap() > recovered);
+    }
+}
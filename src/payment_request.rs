@@ -0,0 +1,275 @@
+use bigdecimal::BigDecimal;
+use chrono::Utc;
+use hyper::{Response, Body, StatusCode};
+use hyper::header::HeaderValue;
+use gotham::handler::HandlerResult;
+use gotham::helpers::http::response::create_response;
+use gotham::state::{FromState, State};
+use secp256k1::{Message, PublicKey, Secp256k1, SecretKey};
+use sha2::{Digest, Sha256};
+
+use crate::bitcoin::{self, Address, Transaction};
+use crate::db;
+use crate::middleware::Env;
+use crate::quote::unix_secs;
+use crate::web::{self, ChargePath};
+
+/// BIP270 payment request served to wallets
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PaymentRequest {
+    network: &'static str,
+    outputs: Vec<Output>,
+    creation_timestamp: u64,
+    expiration_timestamp: u64,
+    memo: String,
+    payment_url: String,
+    merchant_data: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Output {
+    amount: u64,
+    script: String,
+}
+
+/// BIP270 payment submitted by the wallet
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Payment {
+    merchant_data: String,
+    transaction: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refund_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct PaymentAck {
+    payment: Payment,
+    memo: String,
+}
+
+pub fn get_payment_request(state: State) -> (State, Response<Body>) {
+    let res = {
+        let env = Env::borrow_from(&state);
+        let path = ChargePath::borrow_from(&state);
+
+        match web::find_charge(env, &path.charge_id) {
+            Some(ref charge) if !env.db.charge_status(charge).is_payable() => create_response(
+                &state,
+                StatusCode::GONE,
+                mime::TEXT_PLAIN,
+                "Charge is no longer payable",
+            ),
+            Some(ref charge) => match Address::parse(&charge.btc_address) {
+                Ok(address) => match web::locked_quote(env, charge) {
+                    Some(quote) => {
+                        let payment_url = env.base_url
+                            .join(&format!("{}/payment-ack", path.charge_id))
+                            .expect("url construction failed for payment ack");
+
+                        let req = PaymentRequest{
+                            network: address.network.name(),
+                            outputs: vec![Output{
+                                amount: quote.btc_satoshis(),
+                                script: bitcoin::to_hex(&address.script),
+                            }],
+                            creation_timestamp: unix_secs(quote.created_at),
+                            expiration_timestamp: unix_secs(quote.expires_at),
+                            memo: charge.invoice_id.clone(),
+                            payment_url: payment_url.into_string(),
+                            merchant_data: path.charge_id.clone(),
+                        };
+                        let body = serde_json::to_string(&req).unwrap().into_bytes();
+                        let signature = env.payment_request_key.as_ref()
+                            .map(|key| sign(key, &body));
+                        let mut res = create_response(
+                            &state,
+                            StatusCode::OK,
+                            mime::APPLICATION_JSON,
+                            body,
+                        );
+                        if let Some((identity, signature)) = signature {
+                            let headers = res.headers_mut();
+                            headers.insert("x-signature-type", HeaderValue::from_static("ecc"));
+                            headers.insert("x-identity", HeaderValue::from_str(&identity).unwrap());
+                            headers.insert("x-signature", HeaderValue::from_str(&signature).unwrap());
+                        }
+                        res
+                    },
+                    None => create_response(
+                        &state,
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        mime::TEXT_PLAIN,
+                        "Quotes are temporarily unavailable",
+                    ),
+                },
+                Err(err) => {
+                    error!(charge_id = charge.id, hashid = path.charge_id.as_str(); "Charge {} has an unusable BTC address: {:?}", charge.invoice_id, err);
+                    create_response(
+                        &state,
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        mime::TEXT_PLAIN,
+                        "Payment request unavailable",
+                    )
+                },
+            },
+            None => web::not_found(&state),
+        }
+    };
+
+    (state, res)
+}
+
+pub async fn post_payment_ack(mut state: State) -> HandlerResult {
+    match hyper::body::to_bytes(Body::take_from(&mut state)).await {
+        Ok(body) => {
+            let res = payment_ack_response(&state, &body);
+            Ok((state, res))
+        },
+        Err(err) => Err((state, err.into())),
+    }
+}
+
+fn payment_ack_response(state: &State, body: &[u8]) -> Response<Body> {
+    let env = Env::borrow_from(state);
+    let path = ChargePath::borrow_from(state);
+
+    let charge = match web::find_charge(env, &path.charge_id) {
+        Some(charge) => charge,
+        None => return web::not_found(state),
+    };
+    if !env.db.charge_status(&charge).is_payable() {
+        return bad_request(state, "Charge is no longer payable");
+    }
+
+    let payment = match serde_json::from_slice::<Payment>(body) {
+        Ok(payment) => payment,
+        Err(_) => return bad_request(state, "Malformed payment"),
+    };
+    if payment.merchant_data != path.charge_id {
+        return bad_request(state, "Payment is for another payment request");
+    }
+    let tx = match bitcoin::from_hex(&payment.transaction)
+        .and_then(|raw| Transaction::parse(&raw)) {
+        Ok(tx) => tx,
+        Err(_) => return bad_request(state, "Malformed transaction"),
+    };
+    let address = match Address::parse(&charge.btc_address) {
+        Ok(address) => address,
+        Err(err) => {
+            error!(charge_id = charge.id, hashid = path.charge_id.as_str(); "Charge {} has an unusable BTC address: {:?}", charge.invoice_id, err);
+            return create_response(
+                state,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                mime::TEXT_PLAIN,
+                "Payment request unavailable",
+            );
+        },
+    };
+
+    let quote = match env.caches.quotes().read().unwrap().get(&charge.id) {
+        Some(quote) if !quote.is_expired() => quote.clone(),
+        _ => return bad_request(state, "Payment request has expired"),
+    };
+    let paid = tx.satoshis_to(&address.script);
+    if paid < quote.btc_satoshis() {
+        return bad_request(state, "Transaction does not pay the requested amount");
+    }
+
+    // The wallet is still responsible for broadcasting the transaction, so it
+    // is only pending until it is recorded as received
+    info!(
+        charge_id = charge.id, hashid = path.charge_id.as_str(), txid = tx.txid.as_str(), satoshis = paid;
+        "Received payment {} for {} ({} satoshis)", tx.txid, charge.invoice_id, paid
+    );
+    let pending = db::Payment{
+        charge_id: charge.id,
+        txid: tx.txid.clone(),
+        btc_amount: BigDecimal::new(paid.into(), 8),
+        btc_rate: quote.btc_rate.clone(),
+        received_at: Utc::now(),
+        needs_review: false,
+    };
+    if let Err(err) = env.db.record_pending_payment(pending) {
+        error!(charge_id = charge.id, txid = tx.txid.as_str(); "Failed to record pending payment {}: {:?}", tx.txid, err);
+        return create_response(
+            state,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            mime::TEXT_PLAIN,
+            "Payment could not be recorded",
+        );
+    }
+
+    let ack = PaymentAck{
+        payment,
+        memo: format!("Thank you for paying {}", charge.invoice_id),
+    };
+    create_response(
+        state,
+        StatusCode::OK,
+        mime::APPLICATION_JSON,
+        serde_json::to_string(&ack).unwrap().into_bytes(),
+    )
+}
+
+/// Parse the hex encoded secret key payment requests are signed with
+pub fn parse_key(hex: &str) -> Option<SecretKey> {
+    bitcoin::from_hex(hex).ok()
+        .and_then(|raw| SecretKey::from_slice(&raw).ok())
+}
+
+/// Sign a payment request like BitPay's JSON payment protocol does, returns the
+/// compressed public key and the DER encoded ECDSA signature of the body's SHA-256
+/// digest, both hex encoded
+fn sign(key: &SecretKey, body: &[u8]) -> (String, String) {
+    let secp = Secp256k1::signing_only();
+    let mut digest = Sha256::default();
+    digest.input(body);
+    let message = Message::from_slice(digest.result().as_slice())
+        .expect("SHA-256 digest is a valid message");
+    let signature = secp.sign_ecdsa(&message, key);
+    let identity = PublicKey::from_secret_key(&secp, key);
+    (bitcoin::to_hex(&identity.serialize()), bitcoin::to_hex(&signature.serialize_der()))
+}
+
+fn bad_request(state: &State, message: &'static str) -> Response<Body> {
+    create_response(
+        state,
+        StatusCode::BAD_REQUEST,
+        mime::TEXT_PLAIN,
+        message,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::ecdsa::Signature;
+
+    #[test]
+    fn signature_verifies_with_identity() {
+        let key = parse_key("0101010101010101010101010101010101010101010101010101010101010101").unwrap();
+        let body = br#"{"network":"main"}"#;
+        let (identity, signature) = sign(&key, body);
+
+        let secp = Secp256k1::verification_only();
+        let identity = PublicKey::from_slice(&bitcoin::from_hex(&identity).unwrap()).unwrap();
+        let signature = Signature::from_der(&bitcoin::from_hex(&signature).unwrap()).unwrap();
+        let digest = Sha256::digest(body);
+        let message = Message::from_slice(digest.as_slice()).unwrap();
+        assert!(secp.verify_ecdsa(&message, &signature, &identity).is_ok());
+
+        let other = Message::from_slice(Sha256::digest(b"{}").as_slice()).unwrap();
+        assert!(secp.verify_ecdsa(&other, &signature, &identity).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert!(parse_key("not hex").is_none());
+        assert!(parse_key("0101").is_none());
+        assert!(parse_key(&"00".repeat(32)).is_none());
+    }
+}
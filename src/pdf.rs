@@ -0,0 +1,159 @@
+use std::fmt::Write;
+
+/// A4 page size in points
+pub const PAGE_WIDTH: f64 = 595.0;
+pub const PAGE_HEIGHT: f64 = 842.0;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Font {
+    Regular,
+    Bold,
+}
+
+impl Font {
+    fn resource(&self) -> &'static str {
+        match *self {
+            Font::Regular => "F1",
+            Font::Bold => "F2",
+        }
+    }
+}
+
+/// Minimal PDF writer, using only the standard Helvetica fonts and filled
+/// rectangles. Coordinates start from the top-left corner of the current page.
+pub struct Document {
+    pages: Vec<String>,
+}
+
+impl Document {
+    pub fn new() -> Self {
+        Self{
+            pages: vec![String::new()],
+        }
+    }
+
+    /// Continue on a new page, what is drawn next goes on it
+    pub fn new_page(&mut self) {
+        self.pages.push(String::new());
+    }
+
+    fn content(&mut self) -> &mut String {
+        self.pages.last_mut().expect("document has a page")
+    }
+
+    pub fn text(&mut self, x: f64, y: f64, size: f64, font: Font, text: &str) {
+        writeln!(
+            self.content(),
+            "BT /{} {:.2} Tf {:.2} {:.2} Td ({}) Tj ET",
+            font.resource(), size, x, PAGE_HEIGHT - y - size, escape_text(text),
+        ).unwrap();
+    }
+
+    pub fn rect(&mut self, x: f64, y: f64, width: f64, height: f64) {
+        writeln!(
+            self.content(),
+            "{:.2} {:.2} {:.2} {:.2} re f",
+            x, PAGE_HEIGHT - y - height, width, height,
+        ).unwrap();
+    }
+
+    pub fn gray(&mut self, level: f64) {
+        writeln!(self.content(), "{:.2} g", level).unwrap();
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Catalog, page tree and fonts come first, then a page and its contents for every page
+        let first_page = 5;
+        let kids = (0..self.pages.len())
+            .map(|i| format!("{} 0 R", first_page + 2 * i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut objects = vec![
+            "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+            format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids, self.pages.len()),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica \
+             /Encoding /WinAnsiEncoding >>".to_string(),
+            "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold \
+             /Encoding /WinAnsiEncoding >>".to_string(),
+        ];
+        for content in self.pages.iter() {
+            objects.push(format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+                 /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                PAGE_WIDTH, PAGE_HEIGHT, objects.len() + 2,
+            ));
+            objects.push(format!(
+                "<< /Length {} >>\nstream\n{}endstream",
+                content.len(), content,
+            ));
+        }
+
+        let mut out = String::from("%PDF-1.4\n");
+        let mut offsets = vec![];
+        for (i, obj) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            writeln!(out, "{} 0 obj\n{}\nendobj", i + 1, obj).unwrap();
+        }
+
+        let xref_offset = out.len();
+        write!(out, "xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).unwrap();
+        for offset in offsets {
+            writeln!(out, "{:010} 00000 n ", offset).unwrap();
+        }
+        write!(
+            out,
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            objects.len() + 1, xref_offset,
+        ).unwrap();
+
+        out.into_bytes()
+    }
+}
+
+/// Escape text for a PDF string literal, mapping it to WinAnsiEncoding
+/// with octal escapes so that the output stays ASCII.
+fn escape_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            },
+            ' '..='~' => out.push(c),
+            '€' => out.push_str("\\200"),
+            '\u{a0}'..='\u{ff}' => out.push_str(&format!("\\{:03o}", c as u32)),
+            _ => out.push('?'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_special_characters() {
+        assert_eq!(escape_text("(a\\b)"), "\\(a\\\\b\\)");
+        assert_eq!(escape_text("100 € Jüri"), "100 \\200 J\\374ri");
+    }
+
+    #[test]
+    fn xref_offsets_point_at_objects() {
+        let mut doc = Document::new();
+        doc.text(10.0, 10.0, 12.0, Font::Bold, "Receipt");
+        doc.new_page();
+        doc.text(10.0, 10.0, 12.0, Font::Regular, "Continued");
+        let pdf = String::from_utf8(doc.to_bytes()).unwrap();
+
+        assert!(pdf.contains("/Kids [5 0 R 7 0 R] /Count 2"));
+        assert!(pdf.contains("/Contents 8 0 R"));
+        let xref = pdf.rfind("xref\n").unwrap();
+        let entries = pdf[xref..].lines().skip(3).take(8);
+        for (i, entry) in entries.enumerate() {
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(&format!("{} 0 obj", i + 1)));
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! How an EUR amount is turned into the BTC amount that is quoted.
+
+use std::str::FromStr;
+use bigdecimal::{BigDecimal, One, ToPrimitive, Zero};
+
+use crate::coinmotion::WITHDRAWAL_FEE;
+use crate::de::deserialize_big_decimal;
+
+/// How the EUR amount of a charge is turned into the BTC amount quoted to the payer
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PricingConfig {
+    /// Added on top of the amount to cover volatility until the BTC is sold
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub markup_percent: BigDecimal,
+    /// Pass the Coinmotion EUR withdrawal fee on to the payer
+    pub pass_withdrawal_fee: bool,
+    /// Fixed EUR amount added to cover network fees
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub network_fee_eur: BigDecimal,
+    pub rounding: Rounding,
+    /// Significant digits kept by the `significant` rounding
+    pub significant_digits: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rounding {
+    /// As few digits as possible, rounded down so the payer pays up to 1 EUR less
+    Pretty,
+    /// As few digits as possible, rounded up so the payer pays up to 1 EUR more
+    Up,
+    /// Rounded up to `significant_digits` significant digits
+    Significant,
+    /// Rounded up to whole satoshis
+    Exact,
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        Self{
+            markup_percent: BigDecimal::zero(),
+            pass_withdrawal_fee: false,
+            network_fee_eur: BigDecimal::zero(),
+            rounding: Rounding::Pretty,
+            significant_digits: 4,
+        }
+    }
+}
+
+impl PricingConfig {
+    /// EUR amount the payer is asked to pay for the amount due, before rounding
+    pub fn eur_to_quote(&self, eur_amount: &BigDecimal) -> BigDecimal {
+        let hundred = BigDecimal::from_str("100").unwrap();
+        let mut eur = eur_amount.clone() * (hundred.clone() + self.markup_percent.clone()) / hundred;
+        if self.pass_withdrawal_fee {
+            eur += BigDecimal::from_str(WITHDRAWAL_FEE).unwrap();
+        }
+        eur + self.network_fee_eur.clone()
+    }
+
+    /// EUR amount the payer is asked to pay for what is still due out of the charge amount,
+    /// before rounding. The markup and fees of the whole charge are shared out in proportion
+    /// to the amount due, so that paying in parts doesn't add them again.
+    pub fn eur_to_quote_due(&self, eur_due: &BigDecimal, eur_total: &BigDecimal) -> BigDecimal {
+        if eur_total.is_zero() || eur_due >= eur_total {
+            return self.eur_to_quote(eur_due);
+        }
+        self.eur_to_quote(eur_total) * eur_due.clone() / eur_total.clone()
+    }
+
+    pub fn btc_amount(&self, eur_amount: &BigDecimal, btc_bid: &BigDecimal) -> BigDecimal {
+        self.round_btc(self.eur_to_quote(eur_amount), btc_bid)
+    }
+
+    /// BTC amount quoted for what is still due out of the charge amount
+    pub fn btc_amount_due(&self, eur_due: &BigDecimal, eur_total: &BigDecimal, btc_bid: &BigDecimal) -> BigDecimal {
+        self.round_btc(self.eur_to_quote_due(eur_due, eur_total), btc_bid)
+    }
+
+    fn round_btc(&self, eur: BigDecimal, btc_bid: &BigDecimal) -> BigDecimal {
+        match self.rounding {
+            Rounding::Pretty => local_to_pretty_foreign(eur, btc_bid.clone()),
+            Rounding::Up => {
+                // Same number of decimals as the pretty rounding
+                let decimals = btc_bid.to_f64().unwrap().log10().ceil() as i64;
+                round_up(eur / btc_bid.clone(), decimals)
+            },
+            Rounding::Significant => {
+                let btc = eur / btc_bid.clone();
+                if btc.is_zero() {
+                    return btc;
+                }
+                let magnitude = btc.to_f64().unwrap().log10().floor() as i64;
+                let decimals = self.significant_digits as i64 - 1 - magnitude;
+                round_up(btc, decimals.min(8))
+            },
+            Rounding::Exact => round_up(eur / btc_bid.clone(), 8),
+        }
+    }
+}
+
+/// Round a positive value up to the given number of decimals
+fn round_up(value: BigDecimal, decimals: i64) -> BigDecimal {
+    let truncated = value.with_scale(decimals);
+    if truncated < value {
+        let one = BigDecimal::one().into_bigint_and_exponent().0;
+        truncated + BigDecimal::new(one, decimals)
+    } else {
+        truncated
+    }
+}
+
+/// Calculate the foreign currency amount that as few digits as possible
+/// while accepting a loss of up to 1 local unit.
+pub fn local_to_pretty_foreign(local_amount: BigDecimal, foreign_bid: BigDecimal) -> BigDecimal {
+    //    trunc((amount/bid) / 10^floor(log(10, 1/bid))) * 10^floor(log(10, 1/bid))
+    // => trunc((amount/bid) * 10^(-floor(-log(10, bid)))) * 10^floor(-log(10, bid))
+    // => trunc((amount/bid) * 10^(-exp)) * 10^exp
+    let exp = (-foreign_bid.to_f64().unwrap().log10()).floor() as i64;
+    let one = BigDecimal::one().into_bigint_and_exponent().0;
+    // minus_exp = 10^-exp
+    let minus_exp = BigDecimal::new(one.clone(), exp);
+    // plus_exp = 10^exp
+    let plus_exp = BigDecimal::new(one, -exp);
+    ((local_amount / foreign_bid) * minus_exp).with_scale(0) * plus_exp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dec(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    fn pricing(rounding: Rounding) -> PricingConfig {
+        PricingConfig{
+            rounding,
+            ..PricingConfig::default()
+        }
+    }
+
+    #[test]
+    fn rounding_rules() {
+        let bid = dec("40000");
+        let eur = dec("123");
+        assert_eq!(pricing(Rounding::Pretty).btc_amount(&eur, &bid), dec("0.00307"));
+        assert_eq!(pricing(Rounding::Up).btc_amount(&eur, &bid), dec("0.00308"));
+        assert_eq!(pricing(Rounding::Significant).btc_amount(&eur, &bid), dec("0.003075"));
+        assert_eq!(pricing(Rounding::Exact).btc_amount(&eur, &bid), dec("0.003075"));
+        assert_eq!(pricing(Rounding::Exact).btc_amount(&dec("100"), &dec("30000")), dec("0.00333334"));
+    }
+
+    #[test]
+    fn markup_and_fees() {
+        let conf = PricingConfig{
+            markup_percent: dec("2"),
+            pass_withdrawal_fee: true,
+            network_fee_eur: dec("0.5"),
+            rounding: Rounding::Exact,
+            significant_digits: 4,
+        };
+        // 100 * 1.02 + 0.90 + 0.5
+        assert_eq!(conf.eur_to_quote(&dec("100")), dec("103.4"));
+        assert_eq!(conf.btc_amount(&dec("100"), &dec("40000")), dec("0.002585"));
+
+        // Paying 60 EUR of the 100 leaves 40% of the markup and fees to pay
+        assert_eq!(conf.eur_to_quote_due(&dec("40"), &dec("100")), dec("41.36"));
+        assert_eq!(conf.eur_to_quote_due(&dec("100"), &dec("100")), dec("103.4"));
+        assert_eq!(conf.btc_amount_due(&dec("40"), &dec("100"), &dec("40000")), dec("0.001034"));
+    }
+}
@@ -0,0 +1,74 @@
+//! BTC amounts quoted to payers, and how long they stay valid.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bigdecimal::{BigDecimal, ToPrimitive, One, Zero};
+
+use crate::db::round_cents;
+use crate::pricing::PricingConfig;
+
+/// How long the quoted BTC amount stays valid for the payer
+pub const LOCK_IN_SECS: u64 = 15 * 60;
+
+#[derive(Clone, Debug)]
+pub struct Quote {
+    pub eur_amount: BigDecimal,
+    pub btc_amount: BigDecimal,
+    /// EUR/BTC rate the payment is credited at, which includes the pricing markup and fees
+    pub btc_rate: BigDecimal,
+    /// BTC bid the quote was made off
+    pub market_rate: BigDecimal,
+    pub created_at: SystemTime,
+    pub expires_at: SystemTime,
+}
+
+impl Quote {
+    pub fn new(eur_amount: BigDecimal, btc_bid: BigDecimal, pricing: &PricingConfig) -> Self {
+        let eur_total = eur_amount.clone();
+        Self::for_amount_due(eur_amount, &eur_total, btc_bid, pricing)
+    }
+
+    /// Quote what is left to pay of a charge of `eur_total`, with only the due
+    /// amount's share of the markup and fees
+    pub fn for_amount_due(eur_amount: BigDecimal, eur_total: &BigDecimal, btc_bid: BigDecimal, pricing: &PricingConfig) -> Self {
+        let now = SystemTime::now();
+        let btc_amount = pricing.btc_amount_due(&eur_amount, eur_total, &btc_bid);
+        // Paying the quoted BTC amount settles exactly the EUR amount due
+        let btc_rate = if btc_amount.is_zero() {
+            btc_bid.clone()
+        } else {
+            round_cents(eur_amount.clone() / btc_amount.clone())
+        };
+        Self{
+            eur_amount,
+            btc_amount,
+            btc_rate,
+            market_rate: btc_bid,
+            created_at: now,
+            expires_at: now + Duration::from_secs(LOCK_IN_SECS),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= SystemTime::now()
+    }
+
+    /// Seconds left until the quote expires
+    pub fn secs_left(&self) -> u64 {
+        self.expires_at.duration_since(SystemTime::now())
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    pub fn btc_satoshis(&self) -> u64 {
+        let one = BigDecimal::one().into_bigint_and_exponent().0;
+        let mul = BigDecimal::new(one, -8);
+        (self.btc_amount.clone() * mul).with_scale(0)
+            .to_u64().unwrap()
+    }
+}
+
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
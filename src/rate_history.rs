@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, Duration, Utc};
+
+use crate::de::deserialize_big_decimal;
+use crate::ser::serialize_big_decimal;
+
+/// Every rate update, kept in a JSON lines file that is appended to
+pub struct RateHistory {
+    path: PathBuf,
+    retention: Duration,
+    state: RwLock<State>,
+}
+
+struct State {
+    points: VecDeque<RatePoint>,
+    /// Lines in the file, including the ones past retention that haven't been compacted away
+    lines_in_file: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RatePoint {
+    pub at: DateTime<Utc>,
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub btc_bid: BigDecimal,
+    #[serde(serialize_with = "serialize_big_decimal", deserialize_with = "deserialize_big_decimal")]
+    pub btc_ask: BigDecimal,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "Rate history file can't be read or written: {}", err),
+            Error::Parse(ref err) => write!(f, "Rate history point can't be serialized: {}", err),
+        }
+    }
+}
+
+impl RateHistory {
+    pub fn open(path: &str, retention_days: u32) -> Result<Self, Error> {
+        let path = PathBuf::from(path);
+        let mut points = VecDeque::new();
+        let mut lines_in_file = 0;
+
+        match File::open(&path) {
+            Ok(f) => for line in BufReader::new(f).lines() {
+                let line = line.map_err(Error::Io)?;
+                lines_in_file += 1;
+                // A crash while appending can leave a partial last line behind
+                match serde_json::from_str(&line) {
+                    Ok(point) => points.push_back(point),
+                    Err(err) => warn!("Skipping unreadable rate history line: {}", err),
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(Error::Io(err)),
+        }
+
+        let history = Self{
+            path,
+            retention: Duration::days(retention_days as i64),
+            state: RwLock::new(State{
+                points,
+                lines_in_file,
+            }),
+        };
+        {
+            let mut state = history.state.write().unwrap();
+            history.prune(&mut state)?;
+        }
+        Ok(history)
+    }
+
+    pub fn record(&self, btc_bid: BigDecimal, btc_ask: BigDecimal) -> Result<(), Error> {
+        self.record_point(RatePoint{
+            at: Utc::now(),
+            btc_bid,
+            btc_ask,
+        })
+    }
+
+    fn record_point(&self, point: RatePoint) -> Result<(), Error> {
+        let mut state = self.state.write().unwrap();
+
+        let mut line = serde_json::to_vec(&point).map_err(Error::Parse)?;
+        line.push(b'\n');
+        OpenOptions::new().create(true).append(true).open(&self.path)
+            .and_then(|mut f| f.write_all(&line))
+            .map_err(Error::Io)?;
+
+        state.points.push_back(point);
+        state.lines_in_file += 1;
+        self.prune(&mut state)
+    }
+
+    pub fn points(&self) -> Vec<RatePoint> {
+        self.state.read().unwrap().points.iter().cloned().collect()
+    }
+
+    /// Drop the points past retention, the file is rewritten once it's
+    /// mostly made up of them
+    fn prune(&self, state: &mut State) -> Result<(), Error> {
+        let cutoff = Utc::now() - self.retention;
+        while state.points.front().map(|p| p.at < cutoff).unwrap_or(false) {
+            state.points.pop_front();
+        }
+
+        if state.lines_in_file > 2 * state.points.len() + 100 {
+            self.compact(state)?;
+        }
+        Ok(())
+    }
+
+    fn compact(&self, state: &mut State) -> Result<(), Error> {
+        let mut buf = vec![];
+        for point in state.points.iter() {
+            buf.extend(serde_json::to_vec(point).map_err(Error::Parse)?);
+            buf.push(b'\n');
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut f = File::create(&tmp_path).map_err(Error::Io)?;
+            f.write_all(&buf).map_err(Error::Io)?;
+            f.sync_all().map_err(Error::Io)?;
+        }
+        fs::rename(&tmp_path, &self.path).map_err(Error::Io)?;
+        state.lines_in_file = state.points.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use crate::test_util::Scratch;
+
+    fn dec(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn points_survive_reopening() {
+        let scratch = Scratch::new();
+        let path = scratch.path("rates.jsonl");
+        let history = RateHistory::open(&path, 30).unwrap();
+        history.record(dec("40000"), dec("41000")).unwrap();
+        history.record(dec("40100"), dec("41100")).unwrap();
+
+        let history = RateHistory::open(&path, 30).unwrap();
+        let points = history.points();
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[1].btc_bid, dec("40100"));
+    }
+
+    #[test]
+    fn old_points_are_dropped() {
+        let scratch = Scratch::new();
+        let path = scratch.path("rates.jsonl");
+        let history = RateHistory::open(&path, 30).unwrap();
+        for i in 0..150 {
+            history.record_point(RatePoint{
+                at: Utc::now() - Duration::days(40) + Duration::minutes(i),
+                btc_bid: dec("30000"),
+                btc_ask: dec("31000"),
+            }).unwrap();
+        }
+        history.record(dec("40000"), dec("41000")).unwrap();
+        assert_eq!(history.points().len(), 1);
+
+        // The file has been compacted along the way
+        let history = RateHistory::open(&path, 30).unwrap();
+        assert_eq!(history.points().len(), 1);
+        assert!(history.state.read().unwrap().lines_in_file < 151);
+    }
+}
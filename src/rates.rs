@@ -0,0 +1,260 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::str::FromStr;
+use bigdecimal::{BigDecimal, Zero};
+use hyper::{self, Method, Request};
+use serde_json::{self, Value};
+
+use crate::coinmotion::{Client, Rates};
+use crate::de::deserialize_big_decimal;
+use crate::ser::serialize_big_decimal;
+
+/// Public ticker that the Coinmotion rates are checked against
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateSource {
+    pub name: String,
+    /// Ticker URL, `file://` URLs are read from the local disk
+    pub url: String,
+    /// JSON pointer to the EUR bid price in the response, e.g. "/result/XXBTZEUR/b/0"
+    pub bid: String,
+    /// JSON pointer to the EUR ask price in the response
+    pub ask: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RatesConfig {
+    /// How many percent the mid prices of the sources may differ before quoting stops
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub max_deviation_percent: BigDecimal,
+    #[serde(default)]
+    pub sources: Vec<RateSource>,
+    /// JSON lines file every rate update is recorded to
+    #[serde(default = "default_history_path")]
+    pub history_path: String,
+    /// How many days of rate history are kept
+    #[serde(default = "default_history_days")]
+    pub history_days: u32,
+}
+
+fn default_history_path() -> String {
+    "bitcharge-rates.jsonl".to_string()
+}
+
+fn default_history_days() -> u32 {
+    90
+}
+
+impl Default for RatesConfig {
+    fn default() -> Self {
+        Self{
+            max_deviation_percent: BigDecimal::from_str("3").unwrap(),
+            sources: vec![],
+            history_path: default_history_path(),
+            history_days: default_history_days(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Connection(hyper::Error),
+    Io(io::Error),
+    Parse(serde_json::Error),
+    InvalidUrl(String),
+    MissingField(String),
+    InvalidPrice(String),
+    NoSources,
+    Disagreement(Disagreement),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Connection(ref err) => write!(f, "Connection failed: {}", err),
+            Error::Io(ref err) => write!(f, "Ticker file can't be read: {}", err),
+            Error::Parse(ref err) => write!(f, "Ticker isn't valid JSON: {}", err),
+            Error::InvalidUrl(ref err) => write!(f, "Invalid URL: {}", err),
+            Error::MissingField(ref pointer) => write!(f, "No price at {}", pointer),
+            Error::InvalidPrice(ref price) => write!(f, "Invalid price {}", price),
+            Error::NoSources => write!(f, "No rates to compare"),
+            Error::Disagreement(ref d) => write!(f, "Closest source {} is {}% off", d.source, d.deviation_percent),
+        }
+    }
+}
+
+/// How far off the closest source was when the sources last disagreed
+#[derive(Debug, Clone, Serialize)]
+pub struct Disagreement {
+    pub source: String,
+    #[serde(serialize_with = "serialize_big_decimal")]
+    pub deviation_percent: BigDecimal,
+}
+
+pub async fn fetch(client: &Client, source: &RateSource) -> Result<Rates, Error> {
+    if source.url.starts_with("file://") {
+        let body = read_file(&source.url["file://".len()..])?;
+        return parse_ticker(&body, source);
+    }
+
+    let uri = source.url.parse::<hyper::Uri>()
+        .map_err(|err| Error::InvalidUrl(err.to_string()))?;
+    let mut req = Request::new(hyper::Body::empty());
+    *req.method_mut() = Method::GET;
+    *req.uri_mut() = uri;
+
+    let res = client.request(req).await.map_err(Error::Connection)?;
+    let body = hyper::body::to_bytes(res.into_body()).await
+        .map_err(Error::Connection)?;
+    parse_ticker(&body, source)
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(Error::Io)?;
+    Ok(buf)
+}
+
+fn parse_ticker(body: &[u8], source: &RateSource) -> Result<Rates, Error> {
+    let value: Value = serde_json::from_slice(body).map_err(Error::Parse)?;
+    Ok(Rates{
+        btc_bid: price_at(&value, &source.bid)?,
+        btc_ask: price_at(&value, &source.ask)?,
+    })
+}
+
+/// Tickers give prices either as strings or as numbers
+fn price_at(value: &Value, pointer: &str) -> Result<BigDecimal, Error> {
+    let price = match value.pointer(pointer) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Number(n)) => n.to_string(),
+        _ => return Err(Error::MissingField(pointer.to_string())),
+    };
+    let price = BigDecimal::from_str(&price)
+        .map_err(|_| Error::InvalidPrice(price.clone()))?;
+    if price <= BigDecimal::zero() {
+        return Err(Error::InvalidPrice(price.to_string()));
+    }
+    Ok(price)
+}
+
+/// Pick the rates to quote with. The primary (Coinmotion) rates are used as long as at
+/// least one of the other sources agrees with them, or there are no other sources to
+/// check against. Without primary rates the first secondary source is used, as long as
+/// the rest of the sources agree with it.
+pub fn reconcile<'a>(primary: Option<Rates>, secondary: &[(&'a str, Rates)], max_deviation_percent: &BigDecimal)
+    -> Result<(Rates, &'a str), Error>
+{
+    let (rates, name, others) = match primary {
+        Some(rates) => (rates, "coinmotion", secondary),
+        None => match secondary.split_first() {
+            Some((&(name, ref rates), rest)) => (rates.clone(), name, rest),
+            None => return Err(Error::NoSources),
+        },
+    };
+
+    let mut closest: Option<(&str, BigDecimal)> = None;
+    for &(other_name, ref other) in others.iter() {
+        let deviation = deviation_percent(&rates, other);
+        if deviation <= *max_deviation_percent {
+            return Ok((rates, name));
+        }
+        let is_closer = match closest {
+            Some((_, ref d)) => deviation < *d,
+            None => true,
+        };
+        if is_closer {
+            closest = Some((other_name, deviation));
+        }
+    }
+
+    match closest {
+        Some((source, deviation_percent)) => Err(Error::Disagreement(Disagreement{
+            source: source.to_string(),
+            deviation_percent,
+        })),
+        None => Ok((rates, name)),
+    }
+}
+
+/// Difference of the mid prices in percent, mid prices are compared because
+/// the spread of a broker is a lot wider than that of an exchange.
+fn deviation_percent(a: &Rates, b: &Rates) -> BigDecimal {
+    let mid_a = a.btc_bid.clone() + a.btc_ask.clone();
+    let mid_b = b.btc_bid.clone() + b.btc_ask.clone();
+    let hundred = BigDecimal::from_str("100").unwrap();
+    ((mid_a.clone() - mid_b).abs() * hundred / mid_a).with_scale(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::test_util::Scratch;
+
+    fn rates(bid: &str, ask: &str) -> Rates {
+        Rates{
+            btc_bid: BigDecimal::from_str(bid).unwrap(),
+            btc_ask: BigDecimal::from_str(ask).unwrap(),
+        }
+    }
+
+    fn max() -> BigDecimal {
+        BigDecimal::from_str("3").unwrap()
+    }
+
+    #[test]
+    fn primary_is_used_when_a_source_agrees() {
+        let secondary = [("a", rates("40000", "40100")), ("b", rates("30000", "30100"))];
+        let (r, name) = reconcile(Some(rates("39500", "40500")), &secondary, &max()).unwrap();
+        assert_eq!(name, "coinmotion");
+        assert_eq!(r.btc_bid, BigDecimal::from_str("39500").unwrap());
+
+        // Nothing to check against
+        let (_, name) = reconcile(Some(rates("39500", "40500")), &[], &max()).unwrap();
+        assert_eq!(name, "coinmotion");
+    }
+
+    #[test]
+    fn disagreement_is_refused() {
+        let secondary = [("a", rates("30000", "30100")), ("b", rates("35000", "35100"))];
+        match reconcile(Some(rates("39500", "40500")), &secondary, &max()) {
+            Err(Error::Disagreement(d)) => assert_eq!(d.source, "b"),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn falls_back_to_secondary() {
+        let secondary = [("a", rates("40000", "40100")), ("b", rates("40200", "40300"))];
+        let (_, name) = reconcile(None, &secondary, &max()).unwrap();
+        assert_eq!(name, "a");
+
+        assert!(matches!(reconcile(None, &[], &max()), Err(Error::NoSources)));
+    }
+
+    #[test]
+    fn parse_ticker_file() {
+        let scratch = Scratch::new();
+        let path = scratch.path("ticker.json");
+        File::create(&path).unwrap()
+            .write_all(br#"{"result": {"XXBTZEUR": {"b": ["40000.1", "1"], "a": [40010.5, "1"]}}}"#)
+            .unwrap();
+
+        let source = RateSource{
+            name: "file".to_string(),
+            url: format!("file://{}", path),
+            bid: "/result/XXBTZEUR/b/0".to_string(),
+            ask: "/result/XXBTZEUR/a/0".to_string(),
+        };
+        let body = read_file(&source.url["file://".len()..]).unwrap();
+        let r = parse_ticker(&body, &source).unwrap();
+        assert_eq!(r.btc_bid, BigDecimal::from_str("40000.1").unwrap());
+        assert_eq!(r.btc_ask, BigDecimal::from_str("40010.5").unwrap());
+
+        let source = RateSource{bid: "/missing".to_string(), ..source};
+        assert!(parse_ticker(&body, &source).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use qrcode::{self, QrCode, Color};
+
+use crate::conf::BrandingConfig;
+use crate::db::{Charge, Payment};
+use crate::pdf::{Document, Font, PAGE_HEIGHT, PAGE_WIDTH};
+
+const MARGIN: f64 = 56.0;
+
+/// Render the receipt for a paid charge
+pub fn receipt_pdf(charge: &Charge, payments: &[Payment], company_name: Option<&str>, branding: &BrandingConfig) -> Vec<u8> {
+    let mut doc = Document::new();
+    let mut y = header(&mut doc, "Payment receipt", company_name, branding);
+
+    y = field(&mut doc, y, "Invoice", &charge.invoice_id);
+    y = field(&mut doc, y, "Amount", &format!("{} EUR", charge.eur_amount));
+    y = line_items(&mut doc, y, charge);
+
+    for (i, p) in payments.iter().enumerate() {
+        // Keep the title on the same page as the first field of the payment
+        y = make_room(&mut doc, y + 12.0, 60.0);
+        let title = if payments.len() > 1 {
+            format!("Payment {} of {}", i + 1, payments.len())
+        } else {
+            "Payment".to_string()
+        };
+        doc.text(MARGIN, y, 13.0, Font::Bold, &title);
+        y += 24.0;
+
+        let eur_value = (p.btc_amount.clone() * p.btc_rate.clone()).with_scale(2);
+        y = field(&mut doc, y, "Date", &p.received_at.format("%Y-%m-%d %H:%M UTC").to_string());
+        y = field(&mut doc, y, "Amount paid", &format!("{} BTC", p.btc_amount));
+        y = field(&mut doc, y, "Rate used", &format!("{} EUR/BTC", p.btc_rate));
+        y = field(&mut doc, y, "Value", &format!("{} EUR", eur_value));
+        y = field(&mut doc, y, "Transaction", &p.txid);
+    }
+
+    doc.to_bytes()
+}
+
+/// Render payment instructions to attach to an outgoing invoice, the QR code
+/// holds the BIP21 URI of the charge without an amount, as that is only quoted
+/// on the payment page.
+pub fn instructions_pdf(charge: &Charge, pay_url: &str, btc_uri: &str, company_name: Option<&str>, branding: &BrandingConfig) -> Vec<u8> {
+    let mut doc = Document::new();
+    let mut y = header(&mut doc, "Payment instructions", company_name, branding);
+
+    y = field(&mut doc, y, "Invoice", &charge.invoice_id);
+    y = field(&mut doc, y, "Amount", &format!("{} EUR", charge.eur_amount));
+    y = line_items(&mut doc, y, charge);
+    y = field(&mut doc, y, "Bitcoin address", &charge.btc_address);
+    y = field(&mut doc, y, "Payment page", pay_url);
+
+    y = make_room(&mut doc, y, 204.0);
+    doc.text(MARGIN, y, 10.0, Font::Regular,
+        "Scan the QR code with a wallet and pay the amount in BTC shown on the payment page.");
+    qr_code(&mut doc, MARGIN, y + 24.0, 180.0, btc_uri.as_bytes());
+
+    doc.to_bytes()
+}
+
+fn header(doc: &mut Document, title: &str, company_name: Option<&str>, branding: &BrandingConfig) -> f64 {
+    let mut y = MARGIN;
+    let name = branding.legal_name.as_deref().or(company_name);
+    if let Some(name) = name {
+        doc.text(MARGIN, y, 11.0, Font::Bold, name);
+        y += 16.0;
+    }
+    if let Some(ref vat_number) = branding.vat_number {
+        doc.text(MARGIN, y, 10.0, Font::Regular, &format!("VAT number: {}", vat_number));
+        y += 14.0;
+    }
+    if let Some(ref contact_email) = branding.contact_email {
+        doc.text(MARGIN, y, 10.0, Font::Regular, contact_email);
+        y += 14.0;
+    }
+    if y > MARGIN {
+        y += 6.0;
+    }
+    doc.text(MARGIN, y, 22.0, Font::Bold, title);
+    y += 36.0;
+
+    doc.gray(0.85);
+    doc.rect(MARGIN, y, PAGE_WIDTH - 2.0 * MARGIN, 1.0);
+    doc.gray(0.0);
+    y + 20.0
+}
+
+/// Start a new page if the next `height` points don't fit on the current one,
+/// returns where to continue
+fn make_room(doc: &mut Document, y: f64, height: f64) -> f64 {
+    if y + height > PAGE_HEIGHT - MARGIN {
+        doc.new_page();
+        MARGIN
+    } else {
+        y
+    }
+}
+
+fn field(doc: &mut Document, y: f64, label: &str, value: &str) -> f64 {
+    let y = make_room(doc, y, 36.0);
+    doc.gray(0.4);
+    doc.text(MARGIN, y, 9.0, Font::Regular, &label.to_uppercase());
+    doc.gray(0.0);
+    doc.text(MARGIN, y + 12.0, 12.0, Font::Regular, value);
+    y + 36.0
+}
+
+/// Table of the charge line items with the VAT breakdown, nothing for single-amount charges
+fn line_items(doc: &mut Document, mut y: f64, charge: &Charge) -> f64 {
+    if charge.line_items.is_empty() {
+        return y;
+    }
+
+    let amount_x = PAGE_WIDTH - MARGIN - 110.0;
+    for line in charge.line_items.iter() {
+        y = make_room(doc, y, 16.0);
+        doc.text(MARGIN, y, 11.0, Font::Regular, &line.description);
+        doc.text(amount_x, y, 11.0, Font::Regular, &format!("{} EUR", line.net_eur.with_scale(2)));
+        y += 16.0;
+    }
+    doc.gray(0.4);
+    for vat in charge.vat_breakdown() {
+        y = make_room(doc, y, 16.0);
+        doc.text(MARGIN, y, 11.0, Font::Regular,
+            &format!("VAT {}% of {} EUR", vat.vat_rate, vat.net_eur.with_scale(2)));
+        doc.text(amount_x, y, 11.0, Font::Regular, &format!("{} EUR", vat.vat_eur.with_scale(2)));
+        y += 16.0;
+    }
+    doc.gray(0.0);
+    y = make_room(doc, y, 16.0);
+    doc.text(MARGIN, y, 11.0, Font::Bold, "Total");
+    doc.text(amount_x, y, 11.0, Font::Bold, &format!("{} EUR", charge.eur_amount.with_scale(2)));
+    y + 28.0
+}
+
+fn qr_code(doc: &mut Document, x: f64, y: f64, size: f64, data: &[u8]) {
+    let qr = QrCode::with_error_correction_level(
+        data,
+        qrcode::EcLevel::H).expect("Usable QR code");
+
+    let width = qr.width();
+    let module = size / width as f64;
+    for (i, color) in qr.to_colors().into_iter().enumerate() {
+        if color == Color::Dark {
+            let (col, row) = (i % width, i / width);
+            doc.rect(x + col as f64 * module, y + row as f64 * module, module, module);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use bigdecimal::BigDecimal;
+    use chrono::Utc;
+
+    fn dec(s: &str) -> BigDecimal {
+        BigDecimal::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn long_receipt_continues_on_new_pages() {
+        let charge = Charge{
+            id: 1,
+            invoice_id: "2018-0012".to_string(),
+            eur_amount: dec("100"),
+            line_items: vec![],
+            btc_address: "1Archive1n2C579dMsAu3iC6tWzuQJz8dN".to_string(),
+            lightning_invoice: None,
+            language: None,
+            due_date: None,
+            recurrence: None,
+            customer_id: None,
+        };
+        let payments = (0..12).map(|i| Payment{
+            charge_id: 1,
+            txid: format!("tx{}", i),
+            btc_amount: dec("0.001"),
+            btc_rate: dec("5000"),
+            received_at: Utc::now(),
+            needs_review: false,
+        }).collect::<Vec<_>>();
+
+        let pdf = receipt_pdf(&charge, &payments, None, &BrandingConfig::default());
+        let pdf = String::from_utf8(pdf).unwrap();
+        let pages = pdf.matches("/Type /Page /Parent").count();
+        assert!(pages > 2);
+        assert!(pdf.contains(&format!("/Count {}", pages)));
+        assert!(pdf.contains("(Payment 12 of 12)"));
+        // Nothing is drawn below the bottom margin
+        for line in pdf.lines().filter(|l| l.starts_with("BT ")) {
+            let y: f64 = line.split(' ').nth(5).unwrap().parse().unwrap();
+            assert!(y >= MARGIN - 12.0, "{}", line);
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use bigdecimal::BigDecimal;
+use chrono::{Datelike, Duration, NaiveDate};
+
+use crate::db::{Charge, Recurrence};
+use crate::de::deserialize_big_decimal;
+use crate::ser::serialize_big_decimal;
+
+/// Template for charges that are issued to a client every period
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecurringCharge {
+    /// Stable identifier of the template, a charge is issued once per template and period
+    pub id: String,
+    pub client: String,
+    #[serde(default)]
+    pub client_email: Option<String>,
+    /// Customer the issued charges are linked to
+    #[serde(default)]
+    pub customer_id: Option<u64>,
+    /// Invoice id pattern, `{YYYY}`, `{YY}`, `{MM}` and `{DD}` are replaced with the period
+    /// start date and `{N}` with the number of the period, e.g. "RET-{YYYY}-{MM}"
+    pub invoice_id: String,
+    #[serde(deserialize_with = "deserialize_big_decimal")]
+    pub eur_amount: BigDecimal,
+    /// Deposit addresses for the issued charges, each charge gets the first one that
+    /// no other charge uses so that payments for different periods can be told apart
+    pub btc_addresses: Vec<String>,
+    pub schedule: Schedule,
+    /// First day of the first period
+    pub start_date: NaiveDate,
+    /// Days from the period start that the charge can be paid in, leave out for no due date
+    #[serde(default)]
+    pub due_days: Option<u32>,
+    #[serde(default)]
+    pub language: Option<String>,
+    /// URL that is sent the payment link of every new charge
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Schedule {
+    Weekly,
+    Monthly,
+    Quarterly,
+    Yearly,
+}
+
+impl Schedule {
+    /// Start date of the `n`th period, counting from zero
+    pub fn period_start(&self, start: NaiveDate, n: u32) -> NaiveDate {
+        match *self {
+            Schedule::Weekly => start + Duration::weeks(n as i64),
+            Schedule::Monthly => add_months(start, n),
+            Schedule::Quarterly => add_months(start, n * 3),
+            Schedule::Yearly => add_months(start, n * 12),
+        }
+    }
+
+    /// Number and start date of the period that the date falls in,
+    /// `None` when the date is before the first period.
+    pub fn period_of(&self, start: NaiveDate, date: NaiveDate) -> Option<(u32, NaiveDate)> {
+        if date < start {
+            return None;
+        }
+
+        let mut n = 0;
+        loop {
+            if self.period_start(start, n + 1) > date {
+                return Some((n, self.period_start(start, n)));
+            }
+            n += 1;
+        }
+    }
+}
+
+/// Add months to the date, clamping the day to the end of shorter months. Periods are
+/// always counted from the start date, so a charge starting on Jan 31 is issued on
+/// the last day of February and then again on Mar 31.
+fn add_months(date: NaiveDate, months: u32) -> NaiveDate {
+    let total = date.year() * 12 + date.month0() as i32 + months as i32;
+    let (year, month) = (total / 12, (total % 12 + 1) as u32);
+
+    let mut day = date.day();
+    loop {
+        if let Some(date) = NaiveDate::from_ymd_opt(year, month, day) {
+            return date;
+        }
+        day -= 1;
+    }
+}
+
+impl RecurringCharge {
+    /// Charge for the period that the date falls in, the database assigns the id and address
+    pub fn charge_for(&self, date: NaiveDate) -> Option<Charge> {
+        let (n, period) = self.schedule.period_of(self.start_date, date)?;
+
+        Some(Charge{
+            id: 0,
+            invoice_id: self.invoice_id_for(n, period),
+            eur_amount: self.eur_amount.clone(),
+            line_items: vec![],
+            btc_address: String::new(),
+            lightning_invoice: None,
+            language: self.language.clone(),
+            due_date: self.due_days.map(|days| period + Duration::days(days as i64)),
+            recurrence: Some(Recurrence{
+                template: self.id.clone(),
+                period,
+                notified: false,
+                emailed: false,
+            }),
+            customer_id: self.customer_id,
+        })
+    }
+
+    fn invoice_id_for(&self, n: u32, period: NaiveDate) -> String {
+        self.invoice_id
+            .replace("{YYYY}", &format!("{:04}", period.year()))
+            .replace("{YY}", &format!("{:02}", period.year() % 100))
+            .replace("{MM}", &format!("{:02}", period.month()))
+            .replace("{DD}", &format!("{:02}", period.day()))
+            .replace("{N}", &(n + 1).to_string())
+    }
+}
+
+/// Body of the webhook request sent for a newly issued charge
+#[derive(Serialize, Debug)]
+pub struct ChargeNotification<'a> {
+    pub event: &'static str,
+    pub template: &'a str,
+    pub client: &'a str,
+    pub client_email: Option<&'a str>,
+    pub invoice_id: &'a str,
+    #[serde(serialize_with = "serialize_big_decimal")]
+    pub eur_amount: &'a BigDecimal,
+    pub due_date: Option<NaiveDate>,
+    pub url: &'a str,
+}
+
+impl<'a> ChargeNotification<'a> {
+    pub fn new(template: &'a RecurringCharge, charge: &'a Charge, url: &'a str) -> Self {
+        Self{
+            event: "charge.created",
+            template: template.id.as_str(),
+            client: template.client.as_str(),
+            client_email: template.client_email.as_deref(),
+            invoice_id: charge.invoice_id.as_str(),
+            eur_amount: &charge.eur_amount,
+            due_date: charge.due_date,
+            url,
+        }
+    }
+
+    /// Email with the payment link for sendmail, `None` when the client has no email
+    pub fn email(&self, from: &str) -> Option<String> {
+        let to = self.client_email?;
+        let mut body = format!(
+            "Hello {},\r\n\r\nInvoice {} of {} EUR can be paid in bitcoin at\r\n\r\n{}\r\n",
+            self.client, self.invoice_id, self.eur_amount, self.url,
+        );
+        if let Some(due_date) = self.due_date {
+            body.push_str(&format!("\r\nPlease pay it by {}.\r\n", due_date.format("%Y-%m-%d")));
+        }
+        Some(format!(
+            "To: {}\r\nFrom: {}\r\nSubject: Invoice {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+            to, from, self.invoice_id, body,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn template() -> RecurringCharge {
+        RecurringCharge{
+            id: "acme".to_string(),
+            client: "ACME".to_string(),
+            client_email: None,
+            customer_id: Some(7),
+            invoice_id: "RET-{YYYY}-{MM}-{N}".to_string(),
+            eur_amount: BigDecimal::from_str("500").unwrap(),
+            btc_addresses: vec!["1Archive1n2C579dMsAu3iC6tWzuQJz8dN".to_string()],
+            schedule: Schedule::Monthly,
+            start_date: date(2024, 11, 1),
+            due_days: Some(14),
+            language: None,
+            webhook_url: None,
+        }
+    }
+
+    #[test]
+    fn monthly_periods_clamp_to_month_end() {
+        let start = date(2024, 1, 31);
+        assert_eq!(Schedule::Monthly.period_start(start, 1), date(2024, 2, 29));
+        assert_eq!(Schedule::Monthly.period_start(start, 2), date(2024, 3, 31));
+        assert_eq!(Schedule::Quarterly.period_start(start, 4), date(2025, 1, 31));
+        assert_eq!(Schedule::Yearly.period_start(date(2024, 2, 29), 1), date(2025, 2, 28));
+    }
+
+    #[test]
+    fn period_of_date() {
+        let start = date(2024, 1, 15);
+        assert_eq!(Schedule::Monthly.period_of(start, date(2024, 1, 14)), None);
+        assert_eq!(Schedule::Monthly.period_of(start, date(2024, 1, 15)), Some((0, start)));
+        assert_eq!(Schedule::Monthly.period_of(start, date(2024, 3, 14)), Some((1, date(2024, 2, 15))));
+        assert_eq!(Schedule::Weekly.period_of(start, date(2024, 1, 29)), Some((2, date(2024, 1, 29))));
+    }
+
+    #[test]
+    fn charge_for_period() {
+        let template = template();
+        let charge = template.charge_for(date(2025, 1, 20)).unwrap();
+        assert_eq!(charge.invoice_id, "RET-2025-01-3");
+        assert_eq!(charge.due_date, Some(date(2025, 1, 15)));
+        assert_eq!(charge.customer_id, Some(7));
+        assert_eq!(charge.recurrence.unwrap().period, date(2025, 1, 1));
+    }
+
+    #[test]
+    fn email_for_charge() {
+        let mut template = template();
+        let charge = template.charge_for(date(2025, 1, 20)).unwrap();
+        let url = "https://example.com/Xy3kQ1/";
+        assert!(ChargeNotification::new(&template, &charge, url).email("billing@example.com").is_none());
+
+        template.client_email = Some("accounts@acme.example".to_string());
+        let email = ChargeNotification::new(&template, &charge, url).email("billing@example.com").unwrap();
+        assert!(email.starts_with("To: accounts@acme.example\r\nFrom: billing@example.com\r\nSubject: Invoice RET-2025-01-3\r\n"));
+        assert!(email.contains(url));
+        assert!(email.contains("Please pay it by 2025-01-15."));
+    }
+}
@@ -0,0 +1,65 @@
+use hyper::{Response, Body, StatusCode};
+use hyper::header::{HeaderValue, LOCATION};
+use gotham::handler::HandlerResult;
+use gotham::helpers::http::response::create_empty_response;
+use gotham::state::{FromState, State};
+use url::form_urlencoded;
+
+use crate::bitcoin::{Address, Network};
+use crate::middleware::Env;
+use crate::web::{self, ChargePath};
+
+/// Handle the refund address form submitted from the payment page
+pub async fn post_refund(mut state: State) -> HandlerResult {
+    match hyper::body::to_bytes(Body::take_from(&mut state)).await {
+        Ok(body) => {
+            let res = request_refund(&state, &body);
+            Ok((state, res))
+        },
+        Err(err) => Err((state, err.into())),
+    }
+}
+
+fn request_refund(state: &State, body: &[u8]) -> Response<Body> {
+    let env = Env::borrow_from(state);
+    let path = ChargePath::borrow_from(state);
+
+    let charge = match web::find_charge(env, &path.charge_id) {
+        Some(charge) => charge,
+        None => return web::not_found(state),
+    };
+    let mut redirect_url = web::pay_url(env, &charge);
+
+    let address = form_urlencoded::parse(body)
+        .find(|(k, _)| k == "address")
+        .map(|(_, v)| v.trim().to_string())
+        .unwrap_or_default();
+    let valid = Address::parse(&address)
+        .map(|a| a.network == Network::Bitcoin)
+        .unwrap_or(false);
+
+    if !valid {
+        redirect_url.set_query(Some("refund=invalid"));
+    } else {
+        match env.db.request_refund(charge.id, &address) {
+            Ok(Some(refund)) => {
+                info!(
+                    refund_id = refund.id, charge_id = charge.id, hashid = path.charge_id.as_str();
+                    "Refund {} of {} BTC requested for {}", refund.id, refund.btc_amount, charge.invoice_id
+                );
+            },
+            Ok(None) => {},
+            Err(err) => {
+                error!(charge_id = charge.id, hashid = path.charge_id.as_str(); "Failed to record refund request: {:?}", err);
+                redirect_url.set_query(Some("refund=failed"));
+            },
+        }
+    }
+
+    let mut res = create_empty_response(state, StatusCode::SEE_OTHER);
+    res.headers_mut().insert(
+        LOCATION,
+        HeaderValue::from_str(redirect_url.as_str()).unwrap(),
+    );
+    res
+}
@@ -0,0 +1,155 @@
+//! Backoff and circuit breaking for calls to services that may be down.
+
+use std::cmp;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const BACKOFF_BASE_MS: u64 = 500;
+const BACKOFF_MAX_MS: u64 = 10_000;
+
+/// Delay before the given retry (counting from 0), exponential with "full jitter"
+/// so that retries from several callers don't line up
+pub fn backoff(attempt: u32) -> Duration {
+    let ceiling = BACKOFF_BASE_MS.saturating_mul(1 << cmp::min(attempt, 16));
+    let ceiling = cmp::min(ceiling, BACKOFF_MAX_MS);
+    Duration::from_millis(jitter(ceiling))
+}
+
+/// Something between 0 and `max`, the sub-second clock is random enough for spreading out retries
+fn jitter(max: u64) -> u64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos() as u64;
+    nanos % (max + 1)
+}
+
+/// Stops calling a service that keeps failing, so that callers fail fast instead of
+/// piling up timeouts. After the cooldown a single trial call is let through, and
+/// the circuit closes again if it succeeds. A trial that hasn't reported back within
+/// another cooldown is taken as lost, e.g. when its future was dropped, and the next
+/// call becomes the trial instead.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<Circuit>,
+}
+
+enum Circuit {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self{
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(Circuit::Closed{failures: 0}),
+        }
+    }
+
+    /// Whether a call may be made right now
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let trial = match *state {
+            Circuit::Closed{..} => return true,
+            Circuit::Open{until} => now >= until,
+            // Unless the trial call in flight has been lost
+            Circuit::HalfOpen{since} => now >= since + self.cooldown,
+        };
+        if trial {
+            *state = Circuit::HalfOpen{since: now};
+        }
+        trial
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().unwrap() = Circuit::Closed{failures: 0};
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            Circuit::Closed{failures} => failures + 1,
+            // The trial call failed
+            _ => self.failure_threshold,
+        };
+        *state = if failures >= self.failure_threshold {
+            Circuit::Open{until: Instant::now() + self.cooldown}
+        } else {
+            Circuit::Closed{failures}
+        };
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.state.lock().unwrap() {
+            Circuit::Closed{..} => CircuitState::Closed,
+            Circuit::Open{..} => CircuitState::Open,
+            Circuit::HalfOpen{..} => CircuitState::HalfOpen,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn backoff_is_capped() {
+        for attempt in 0..40 {
+            let ceiling = cmp::min(BACKOFF_BASE_MS << cmp::min(attempt, 16), BACKOFF_MAX_MS);
+            assert!(backoff(attempt) <= Duration::from_millis(ceiling));
+        }
+    }
+
+    #[test]
+    fn circuit_opens_and_recovers() {
+        let circuit = CircuitBreaker::new(3, Duration::from_secs(3600));
+        circuit.record_failure();
+        circuit.record_failure();
+        assert_eq!(circuit.state(), CircuitState::Closed);
+        circuit.record_failure();
+        assert_eq!(circuit.state(), CircuitState::Open);
+        assert!(!circuit.allow());
+
+        // After the cooldown the next call is a trial
+        let circuit = CircuitBreaker::new(1, Duration::from_millis(20));
+        circuit.record_failure();
+        thread::sleep(Duration::from_millis(25));
+        assert!(circuit.allow());
+        assert_eq!(circuit.state(), CircuitState::HalfOpen);
+        assert!(!circuit.allow());
+        circuit.record_failure();
+        assert_eq!(circuit.state(), CircuitState::Open);
+        thread::sleep(Duration::from_millis(25));
+        assert!(circuit.allow());
+        circuit.record_success();
+        assert_eq!(circuit.state(), CircuitState::Closed);
+        assert!(circuit.allow());
+    }
+
+    #[test]
+    fn lost_trial_call_is_replaced() {
+        let circuit = CircuitBreaker::new(1, Duration::from_millis(20));
+        circuit.record_failure();
+        thread::sleep(Duration::from_millis(25));
+
+        // The trial call is dropped without reporting back
+        assert!(circuit.allow());
+        assert!(!circuit.allow());
+        thread::sleep(Duration::from_millis(25));
+        assert!(circuit.allow());
+        assert_eq!(circuit.state(), CircuitState::HalfOpen);
+        circuit.record_success();
+        assert_eq!(circuit.state(), CircuitState::Closed);
+    }
+}
@@ -0,0 +1,17 @@
+use bigdecimal::BigDecimal;
+use serde::Serializer;
+
+pub fn serialize_big_decimal<S>(value: &BigDecimal, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    serializer.collect_str(value)
+}
+
+pub fn serialize_opt_big_decimal<S>(value: &Option<BigDecimal>, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    match *value {
+        Some(ref value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
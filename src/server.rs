@@ -0,0 +1,82 @@
+use std::sync::Arc;
+use url::Url;
+
+use crate::cache::Caches;
+use crate::conf;
+use crate::db;
+use crate::i18n;
+use crate::logging;
+use crate::payment_request;
+use crate::middleware::EnvMiddleware;
+use crate::rate_history;
+use crate::web;
+use crate::worker;
+
+/// Load the config, start the task worker and serve the web pages, this blocks
+/// for as long as the server runs
+pub fn run() {
+    let conf = conf::load();
+    logging::init(conf.log.format);
+    let db = db::Database::open(conf.charges, conf.customers, &conf.db.path, conf.payments.tolerance_eur)
+        .expect("database file isn't usable");
+    let db = Arc::new(db);
+    let caches = Arc::new(Caches::new());
+    let rate_history = rate_history::RateHistory::open(&conf.rates.history_path, conf.rates.history_days)
+        .expect("rate history file isn't usable");
+    let rate_history = Arc::new(rate_history);
+    let hashids = harsh::HarshBuilder::new()
+        .salt(conf.web.hashids_salt)
+        .length(6)
+        .init().unwrap();
+    // Base URL is assumed to always be a directory, so make sure
+    // that it ends with a path separator to avoid surprises
+    let base_url = if conf.web.base_url.ends_with("/") {
+        conf.web.base_url
+    } else {
+        format!("{}/", conf.web.base_url)
+    };
+    let base_url = Url::parse(&base_url)
+        .expect("invalid base_url in config");
+
+    let payment_request_key = conf.web.payment_request_key.as_ref()
+        .map(|key| payment_request::parse_key(key).expect("invalid payment_request_key in config"));
+    if payment_request_key.is_none() {
+        warn!("No payment_request_key configured, payment requests are served unsigned");
+    }
+
+    for c in db.charges().iter() {
+        let url = web::charge_url(&hashids, &base_url, c.id);
+        info!(charge_id = c.id, invoice_id = c.invoice_id.as_str(); "Serving {} ({} EUR) at {}", c.invoice_id, c.eur_amount, url);
+    }
+
+    info!("Initialising task worker...");
+    let issuer = worker::Issuer{
+        templates: conf.recurring,
+        hashids: hashids.clone(),
+        base_url: base_url.clone(),
+        mail: conf.mail,
+    };
+    if !worker::start(conf.coinmotion, conf.rates, conf.alerts, caches.clone(), rate_history.clone(), db.clone(), issuer) {
+        error!("Failed to initialise the task worker!");
+        return;
+    }
+
+    let addr = format!("127.0.0.1:{}", conf.web.http_port);
+    let router = web::router(EnvMiddleware{
+        db,
+        caches,
+        rate_history,
+        hashids,
+        company_name: conf.web.company_name,
+        payment_request_key,
+        base_url,
+        admin_token: conf.admin.token,
+        catalogs: Arc::new(i18n::Catalogs::load()),
+        branding: Arc::new(conf.branding),
+        pricing: Arc::new(conf.pricing),
+    });
+    if let Err(err) = gotham::start(addr, router) {
+        error!("Failed to start the web server: {:?}", err);
+    }
+}
+
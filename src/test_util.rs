@@ -0,0 +1,23 @@
+//! Helpers shared by the unit tests
+
+use tempfile::TempDir;
+
+/// Scratch directory for the files written by a test, removed when it is dropped
+pub struct Scratch {
+    dir: TempDir,
+}
+
+impl Scratch {
+    pub fn new() -> Self {
+        let dir = tempfile::Builder::new()
+            .prefix("bitcharge-test-")
+            .tempdir()
+            .expect("scratch directory can't be created");
+        Self{ dir }
+    }
+
+    /// Path of the named file inside the directory
+    pub fn path(&self, name: &str) -> String {
+        self.dir.path().join(name).to_str().unwrap().to_string()
+    }
+}
@@ -1,69 +1,263 @@
-use std::sync::Arc;
-use bigdecimal::{BigDecimal, ToPrimitive, One};
-use hyper::{Response, Body, StatusCode};
+use hyper::{Response, Body, HeaderMap, StatusCode};
+use hyper::header::ACCEPT_LANGUAGE;
 use askama::Template;
 use gotham::helpers::http::response::create_response;
 use gotham::router::Router;
 use gotham::router::builder::*;
 use gotham::state::{FromState, State};
 use gotham::pipeline::new_pipeline;
-use gotham::pipeline::single::single_pipeline;
-use mime;
-use harsh::Harsh;
+use gotham::pipeline::single_pipeline;
 use qrcode::{self, QrCode};
 use qrcode::render::svg;
-use base64;
+use url::Url;
+use harsh::Harsh;
+use bigdecimal::{BigDecimal, Zero};
+
+use crate::admin::{self, AdminChargePath, AdminCustomerPath, AdminRefundPath, FormatQuery};
+use crate::bip21::Bip21Uri;
+use crate::conf::BrandingConfig;
+use crate::customer;
+use crate::db::{Charge, ChargeStatus, Customer};
+use crate::health;
+use crate::i18n::{self, Messages};
+use crate::middleware::{Env, EnvMiddleware, SpanMiddleware};
+use crate::payment_request;
+use crate::quote::Quote;
+use crate::receipt;
+use crate::refund;
 
-use cache::Caches;
-use db::Database;
-use middleware::{Env, EnvMiddleware};
+// Harsh currently panics on invalid alphabet input, so work-around it by only accepting
+// valid alphabet in the charge_id path component
+const CHARGE_ID_SEGMENT: &str = ":charge_id:[abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890]+";
+const CUSTOMER_ID_SEGMENT: &str = ":customer_id:[abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890]+";
 
-pub fn router(db: Arc<Database>, caches: Arc<Caches>, hashids: Harsh) -> Router {
+// Customer hashids are prefixed with this, so that they can't be mixed up with charge hashids
+const CUSTOMER_HASHID_TAG: u64 = 1;
+
+pub fn router(env: EnvMiddleware) -> Router {
+    let static_dir = env.branding.static_dir.clone();
     let (chain, pipeline) = single_pipeline(new_pipeline()
-        .add(EnvMiddleware{
-            db,
-            caches,
-            hashids,
-        })
+        .add(SpanMiddleware)
+        .add(env)
         .build());
 
     build_router(chain, pipeline, |route| {
-        // Harsh currently panics on invalid alphabet input, so work-around it by only accepting
-        // valid alphabet in the charge_id path component
-        route.get_or_head("/:charge_id:[abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890]+")
-            .with_path_extractor::<PayNowPath>()
+        if let Some(static_dir) = static_dir {
+            route.get("/static/*")
+                .to_dir(static_dir);
+        }
+
+        route.get("/health")
+            .to(health::get_health);
+        route.get("/healthz")
+            .to(health::get_health);
+        route.get("/readyz")
+            .to(health::get_readyz);
+        route.get("/metrics")
+            .to(health::get_metrics);
+
+        route.get_or_head(&format!("/{}", CHARGE_ID_SEGMENT))
+            .with_path_extractor::<ChargePath>()
+            .with_query_string_extractor::<PayNowQuery>()
             .to(get_pay_now_page);
+        route.get(&format!("/{}/payment-request", CHARGE_ID_SEGMENT))
+            .with_path_extractor::<ChargePath>()
+            .to(payment_request::get_payment_request);
+        route.post(&format!("/{}/payment-ack", CHARGE_ID_SEGMENT))
+            .with_path_extractor::<ChargePath>()
+            .to_async(payment_request::post_payment_ack);
+        route.post(&format!("/{}/refund", CHARGE_ID_SEGMENT))
+            .with_path_extractor::<ChargePath>()
+            .to_async(refund::post_refund);
+        route.get(&format!("/c/{}", CUSTOMER_ID_SEGMENT))
+            .with_path_extractor::<CustomerPath>()
+            .with_query_string_extractor::<LangQuery>()
+            .to(customer::get_customer_page);
+        route.get(&format!("/{}/receipt.pdf", CHARGE_ID_SEGMENT))
+            .with_path_extractor::<ChargePath>()
+            .to(get_receipt);
+        route.get(&format!("/{}/instructions.pdf", CHARGE_ID_SEGMENT))
+            .with_path_extractor::<ChargePath>()
+            .to(get_instructions);
+
+        route.scope("/admin", |route| {
+            route.get("/charges")
+                .to(admin::get_charges);
+            route.post("/charges/:id/payments")
+                .with_path_extractor::<AdminChargePath>()
+                .to_async(admin::post_payment);
+            route.get("/customers")
+                .to(admin::get_customers);
+            route.get("/customers/:id/charges")
+                .with_path_extractor::<AdminCustomerPath>()
+                .to(admin::get_customer_charges);
+            route.get("/ledger")
+                .to(admin::get_ledger);
+            route.get("/refunds")
+                .to(admin::get_refunds);
+            route.get("/rates")
+                .with_query_string_extractor::<FormatQuery>()
+                .to(admin::get_rates);
+            route.get("/rates/chart")
+                .to(admin::get_rates_chart);
+            route.get("/sales")
+                .with_query_string_extractor::<FormatQuery>()
+                .to(admin::get_sales);
+            route.get("/exchanges")
+                .to(admin::get_exchanges);
+            route.post("/refunds/:id/approve")
+                .with_path_extractor::<AdminRefundPath>()
+                .to(admin::post_approve_refund);
+            route.post("/refunds/:id/reject")
+                .with_path_extractor::<AdminRefundPath>()
+                .to(admin::post_reject_refund);
+            route.post("/charges/:id/cancel")
+                .with_path_extractor::<AdminChargePath>()
+                .to(admin::post_cancel);
+            route.post("/charges/:id/void")
+                .with_path_extractor::<AdminChargePath>()
+                .to(admin::post_void);
+            route.get("/charges/:id/receipt.pdf")
+                .with_path_extractor::<AdminChargePath>()
+                .to(admin::get_receipt);
+            route.get("/charges/:id/instructions.pdf")
+                .with_path_extractor::<AdminChargePath>()
+                .to(admin::get_instructions);
+        });
     })
 }
 
 #[derive(Deserialize, StateData, StaticResponseExtender)]
-struct PayNowPath {
-    charge_id: String,
+pub struct ChargePath {
+    pub charge_id: String,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct CustomerPath {
+    pub customer_id: String,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct LangQuery {
+    pub lang: Option<String>,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct PayNowQuery {
+    lang: Option<String>,
+    refund: Option<String>,
 }
 
 #[derive(Template)]
 #[template(path = "pay_now.html")]
 struct PayNowTemplate<'a> {
+    lang: &'a str,
+    t: &'a Messages,
+    branding: &'a BrandingConfig,
+    static_url: String,
     invoice_id: &'a str,
     btc_address: &'a str,
     btc_amount: String,
     btc_link: String,
     qr_code_uri: String,
+    lock_in_secs: u64,
+    paid: bool,
+    partially_paid: bool,
+    closed: bool,
+    quote_unavailable: bool,
+    receipt_url: String,
+    refund_btc: String,
+    refund_pending: bool,
+    refund_invalid: bool,
+    refund_url: String,
+    line_items: Vec<LineItemRow>,
+    vat_lines: Vec<VatRow>,
+    total_eur: String,
+}
+
+struct LineItemRow {
+    description: String,
+    net_eur: String,
+}
+
+struct VatRow {
+    vat_rate: String,
+    vat_eur: String,
+}
+
+/// Look up the charge referenced by the hashid in the request path
+pub fn find_charge(env: &Env, charge_id: &str) -> Option<Charge> {
+    env.hashids.decode(charge_id)
+        .and_then(|s| if s.len() == 1 { Some(s[0]) } else { None })
+        .and_then(|id| env.db.get_charge_by_id(id))
+}
+
+/// Look up the customer referenced by the hashid in the request path
+pub fn find_customer<'a>(env: &'a Env, customer_id: &str) -> Option<&'a Customer> {
+    env.hashids.decode(customer_id)
+        .and_then(|s| match s.as_slice() {
+            &[CUSTOMER_HASHID_TAG, id] => Some(id),
+            _ => None,
+        })
+        .and_then(|id| env.db.get_customer_by_id(id))
+}
+
+/// Public URL of the charge payment page
+pub fn pay_url(env: &Env, charge: &Charge) -> Url {
+    charge_url(&env.hashids, &env.base_url, charge.id)
+}
+
+pub fn charge_url(hashids: &Harsh, base_url: &Url, charge_id: u64) -> Url {
+    let hid = hashids.encode(&[charge_id]).expect("invalid hashid for charge");
+    base_url.join(&format!("{}/", hid))
+        .expect("url construction failed for charge")
+}
+
+/// Public URL of the page listing the customer's invoices
+pub fn customer_url(env: &Env, customer: &Customer) -> Url {
+    let hid = env.hashids.encode(&[CUSTOMER_HASHID_TAG, customer.id])
+        .expect("invalid hashid for customer");
+    env.base_url.join(&format!("c/{}/", hid))
+        .expect("url construction failed for customer")
+}
+
+/// Quote for the charge that is locked in for the payer, a new quote
+/// is only made once the previous one has expired. Returns `None` when
+/// the rate sources disagree and no new quotes can be made.
+pub fn locked_quote(env: &Env, charge: &Charge) -> Option<Quote> {
+    if let Some(quote) = env.caches.quotes().read().unwrap().get(&charge.id) {
+        if !quote.is_expired() {
+            return Some(quote.clone());
+        }
+    }
+
+    if !*env.caches.rates_trusted().read().unwrap() {
+        return None;
+    }
+    let btc_bid = env.caches.rates().read().unwrap().try_get()?.btc_bid;
+    let quote = Quote::for_amount_due(env.db.amount_due(charge), &charge.eur_amount, btc_bid, &env.pricing);
+    env.caches.quotes().write().unwrap().insert(charge.id, quote.clone());
+    Some(quote)
+}
+
+/// URL prefix of the files served from the branding static directory
+pub fn static_url(env: &Env) -> String {
+    env.base_url.join("static/")
+        .expect("url construction failed for static files")
+        .into_string()
 }
 
-/// Calculate the foreign currency amount that as few digits as possible
-/// while accepting a loss of up to 1 local unit.
-fn local_to_pretty_foreign(local_amount: BigDecimal, foreign_bid: BigDecimal) -> BigDecimal {
-    //    trunc((amount/bid) / 10^floor(log(10, 1/bid))) * 10^floor(log(10, 1/bid))
-    // => trunc((amount/bid) * 10^(-floor(-log(10, bid)))) * 10^floor(-log(10, bid))
-    // => trunc((amount/bid) * 10^(-exp)) * 10^exp
-    let exp = (-foreign_bid.to_f64().unwrap().log10()).floor() as i64;
-    let one = BigDecimal::one().into_bigint_and_exponent().0;
-    // minus_exp = 10^-exp
-    let minus_exp = BigDecimal::new(one.clone(), exp);
-    // plus_exp = 10^exp
-    let plus_exp = BigDecimal::new(one, -exp);
-    ((local_amount / foreign_bid) * minus_exp).with_scale(0) * plus_exp
+/// BIP21 URI of the charge, without an amount as that depends on the quote
+fn btc_uri(env: &Env, charge: &Charge) -> Bip21Uri {
+    let mut uri = Bip21Uri::new(&charge.btc_address)
+        .message(&charge.invoice_id);
+    if let Some(ref company_name) = env.company_name {
+        uri = uri.label(company_name);
+    }
+    if let Some(ref invoice) = charge.lightning_invoice {
+        uri = uri.lightning(invoice);
+    }
+    uri
 }
 
 fn qr_code_uri(data: &[u8]) -> String {
@@ -84,29 +278,92 @@ fn qr_code_uri(data: &[u8]) -> String {
 fn get_pay_now_page(state: State) -> (State, Response<Body>) {
     let res = {
         let env = Env::borrow_from(&state);
-        let path = PayNowPath::borrow_from(&state);
+        let path = ChargePath::borrow_from(&state);
+        let query = PayNowQuery::borrow_from(&state);
 
-        let charge = env.hashids.decode(&path.charge_id)
-            .and_then(|s| s.first().map(|i| *i))
-            .and_then(|id| env.db.get_charge_by_id(id));
+        if let Some(ref charge) = find_charge(env, &path.charge_id) {
+            *env.caches.pay_page_hits().write().unwrap()
+                .entry(charge.id).or_insert(0) += 1;
+            let status = env.db.charge_status(charge);
+            let customer = charge.customer_id
+                .and_then(|id| env.db.get_customer_by_id(id));
+            let lang = i18n::negotiate(
+                query.lang.as_deref(),
+                HeaderMap::borrow_from(&state).get(ACCEPT_LANGUAGE)
+                    .and_then(|v| v.to_str().ok()),
+                charge.language.as_ref()
+                    .or_else(|| customer.and_then(|c| c.language.as_ref()))
+                    .map(String::as_str),
+            );
 
-        if let Some(charge) = charge {
-            let btc_bid = env.caches.rates().read().unwrap().get().btc_bid;
-            let eur_amount = charge.eur_amount.clone();
-            let btc_amount = local_to_pretty_foreign(eur_amount, btc_bid);
+            let refund_btc = env.db.refundable_btc(charge.id);
 
             let html = {
                 let btc_address = charge.btc_address.as_str();
-                let btc_amount = format!("{}", btc_amount);
-                let btc_link = format!("bitcoin:{}?amount={}", btc_address, btc_amount);
-                let qr_code_uri = qr_code_uri(btc_link.as_bytes());
+                // Only quote an amount while the charge can still be paid
+                let quote = if status.is_payable() {
+                    locked_quote(env, charge)
+                } else {
+                    None
+                };
+                let quote_unavailable = status.is_payable() && quote.is_none();
+                let (btc_amount, btc_link, qr_code_uri, lock_in_secs) = if let Some(quote) = quote {
+                    let btc_link = btc_uri(env, charge)
+                        .amount(quote.btc_amount.clone())
+                        .to_string();
+                    let qr_code_uri = qr_code_uri(btc_link.as_bytes());
+                    (
+                        i18n::format_decimal(&quote.btc_amount.to_string(), lang),
+                        btc_link,
+                        qr_code_uri,
+                        quote.secs_left(),
+                    )
+                } else {
+                    (String::new(), String::new(), String::new(), 0)
+                };
 
                 PayNowTemplate{
+                    lang: lang.code(),
+                    t: env.catalogs.get(lang),
+                    branding: &env.branding,
+                    static_url: static_url(env),
                     invoice_id: charge.invoice_id.as_str(),
                     btc_address,
                     btc_amount,
                     btc_link,
                     qr_code_uri,
+                    lock_in_secs,
+                    paid: status == ChargeStatus::Paid,
+                    partially_paid: status == ChargeStatus::PartiallyPaid,
+                    closed: !status.is_payable() && status != ChargeStatus::Paid,
+                    quote_unavailable,
+                    receipt_url: pay_url(env, charge).join("receipt.pdf")
+                        .expect("url construction failed for receipt")
+                        .into_string(),
+                    refund_btc: if refund_btc > BigDecimal::zero() {
+                        i18n::format_decimal(&refund_btc.to_string(), lang)
+                    } else {
+                        String::new()
+                    },
+                    refund_pending: env.db.refunds_for(charge.id).iter()
+                        .any(|r| r.status.is_unsent()),
+                    refund_invalid: query.refund.as_deref() == Some("invalid"),
+                    refund_url: pay_url(env, charge).join("refund")
+                        .expect("url construction failed for refund")
+                        .into_string(),
+                    line_items: charge.line_items.iter()
+                        .map(|l| LineItemRow{
+                            description: l.description.clone(),
+                            net_eur: i18n::format_decimal(&l.net_eur.with_scale(2).to_string(), lang),
+                        })
+                        .collect(),
+                    vat_lines: charge.vat_breakdown().iter()
+                        .map(|v| VatRow{
+                            vat_rate: i18n::format_decimal(&v.vat_rate.to_string(), lang),
+                            vat_eur: i18n::format_decimal(&v.vat_eur.with_scale(2).to_string(), lang),
+                        })
+                        .collect(),
+                    total_eur: i18n::format_decimal(&charge.eur_amount.with_scale(2).to_string(), lang),
                 }.render().unwrap()
             };
             create_response(
@@ -116,15 +373,84 @@ fn get_pay_now_page(state: State) -> (State, Response<Body>) {
                 html.into_bytes(),
             )
         } else {
-            create_response(
-                &state,
-                StatusCode::NOT_FOUND,
-                mime::TEXT_PLAIN,
-                "Not found",
-            )
+            not_found(&state)
+        }
+    };
+
+    (state, res)
+}
+
+fn get_receipt(state: State) -> (State, Response<Body>) {
+    let res = {
+        let env = Env::borrow_from(&state);
+        let path = ChargePath::borrow_from(&state);
+
+        match find_charge(env, &path.charge_id) {
+            Some(ref charge) => receipt_response(&state, charge),
+            None => not_found(&state),
         }
     };
 
     (state, res)
 }
 
+fn get_instructions(state: State) -> (State, Response<Body>) {
+    let res = {
+        let env = Env::borrow_from(&state);
+        let path = ChargePath::borrow_from(&state);
+
+        match find_charge(env, &path.charge_id) {
+            Some(ref charge) => instructions_response(&state, charge),
+            None => not_found(&state),
+        }
+    };
+
+    (state, res)
+}
+
+/// Receipt for the charge, only available once it has been paid
+pub fn receipt_response(state: &State, charge: &Charge) -> Response<Body> {
+    let env = Env::borrow_from(state);
+    if env.db.charge_status(charge) != ChargeStatus::Paid {
+        return not_found(state);
+    }
+    let payments = env.db.payments_for(charge.id);
+
+    let pdf = receipt::receipt_pdf(
+        charge,
+        &payments,
+        env.company_name.as_deref(),
+        &env.branding,
+    );
+    pdf_response(state, pdf)
+}
+
+pub fn instructions_response(state: &State, charge: &Charge) -> Response<Body> {
+    let env = Env::borrow_from(state);
+    let pdf = receipt::instructions_pdf(
+        charge,
+        pay_url(env, charge).as_str(),
+        &btc_uri(env, charge).to_string(),
+        env.company_name.as_deref(),
+        &env.branding,
+    );
+    pdf_response(state, pdf)
+}
+
+fn pdf_response(state: &State, pdf: Vec<u8>) -> Response<Body> {
+    create_response(
+        state,
+        StatusCode::OK,
+        mime::APPLICATION_PDF,
+        pdf,
+    )
+}
+
+pub fn not_found(state: &State) -> Response<Body> {
+    create_response(
+        state,
+        StatusCode::NOT_FOUND,
+        mime::TEXT_PLAIN,
+        "Not found",
+    )
+}
@@ -1,60 +1,153 @@
+use std::cell::RefCell;
 use std::str::FromStr;
 use std::sync::mpsc::sync_channel;
 use std::sync::Arc;
 use std::thread;
-use std::time::{SystemTime, Duration};
+use std::time::{Duration, SystemTime};
 use bigdecimal::{BigDecimal, Zero, One, ToPrimitive};
+use chrono::{DateTime, Utc};
+use harsh::Harsh;
 use hyper_tls::HttpsConnector;
-use futures::{self, Future, Stream};
-use tokio_timer::Interval;
-use tokio::runtime::current_thread;
-use hyper;
+use futures::future::{self, FutureExt, LocalBoxFuture};
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::runtime;
+use tokio::time::{self, Instant};
+use hyper::{self, Method, Request};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use url::Url;
 
-use conf::CoinmotionConfig;
-use coinmotion::{Coinmotion, BuySellAmount, WITHDRAWAL_FEE};
-use cache::Caches;
+use crate::alerts::{self, Alerter, AlertsConfig, Channel, Observed};
+use crate::conf::{CoinmotionConfig, MailConfig};
+use crate::coinmotion::{self, Balances, Client, Coinmotion, BuySellAmount, HistoryPage, Trade, Transaction, WITHDRAWAL_FEE};
+use crate::cache::Caches;
+use crate::db::{Charge, Database, ExchangeAction, ExchangeKind, Refund, RefundStatus, Sale};
+use crate::logging;
+use crate::nonce::Nonces;
+use crate::rate_history::RateHistory;
+use crate::rates::{self, RatesConfig};
+use crate::recurring::{ChargeNotification, RecurringCharge};
+use crate::web;
 
-type Client = hyper::Client<HttpsConnector<hyper::client::HttpConnector>, hyper::Body>;
+/// Recurring charge templates, along with what is needed to link to the issued charges
+pub struct Issuer {
+    pub templates: Vec<RecurringCharge>,
+    pub hashids: Harsh,
+    pub base_url: Url,
+    /// Emails the payment links to the clients when set
+    pub mail: Option<MailConfig>,
+}
 
-pub fn start(cm_conf: CoinmotionConfig, caches: Arc<Caches>) -> bool {
-    let caches_outer = caches;
+pub fn start(cm_conf: CoinmotionConfig, rates_conf: RatesConfig, alerts_conf: AlertsConfig, caches: Arc<Caches>, history: Arc<RateHistory>, db: Arc<Database>, issuer: Issuer) -> bool {
+    let nonces = match Nonces::open(&cm_conf.nonce_path) {
+        Ok(nonces) => Arc::new(nonces),
+        Err(err) => {
+            error!("Nonce file isn't usable: {:?}", err);
+            return false;
+        },
+    };
     let (tx, rx) = sync_channel(0);
 
     thread::spawn(move || {
-        let https = HttpsConnector::new(4).unwrap();
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
         let client: Client = hyper::Client::builder()
-            .keep_alive(false)
-            .build::<_, hyper::Body>(https);
-
-        let api = Coinmotion::new(
-            &client,
-            cm_conf.api_key.as_str(),
-            cm_conf.api_secret.as_str(),
-        );
+            .pool_max_idle_per_host(0)
+            .build::<_, hyper::Body>(HttpsConnector::new());
+
+        let api = Coinmotion::new(&client, cm_conf.api_key.as_str(), cm_conf.api_secret.as_str(), nonces)
+            .circuit(caches.coinmotion_circuit().clone())
+            .stats(caches.coinmotion_calls().clone());
         let api = &api;
+        let client = &client;
+        let issuer = &issuer;
+        let rates_conf = &rates_conf;
+        let channels = &alerts_conf.channels;
+        let alerter = &RefCell::new(Alerter::new(alerts_conf.rules.clone(), Utc::now()));
+
+        let mut scheduler = Scheduler::new(caches.clone());
+        let update_rates = {
+            let caches = caches.clone();
+            let history = history.clone();
+            scheduler.add(Job{
+                name: "update_rates",
+                every: Duration::from_secs(UPDATE_RATES_INTERVAL_SECS),
+                first_run: Duration::from_secs(UPDATE_RATES_INTERVAL_SECS),
+                timeout: Duration::from_secs(2 * 60),
+                concurrency: 1,
+                run: Box::new(move || {
+                    update_rates_task(api, client, rates_conf, caches.clone(), history.clone()).boxed_local()
+                }),
+            })
+        };
+        let exchange = {
+            let caches = caches.clone();
+            let db = db.clone();
+            scheduler.add(Job{
+                name: "exchange",
+                every: Duration::from_secs(EXCHANGE_INTERVAL_SECS),
+                first_run: Duration::from_secs(EXCHANGE_INTERVAL_SECS),
+                timeout: Duration::from_secs(4 * 60),
+                concurrency: 1,
+                run: Box::new(move || {
+                    let caches = caches.clone();
+                    let db = db.clone();
+                    async move {
+                        // Send out refunds before any of the reserved BTC could be sold
+                        refunds_task(api, caches.clone(), db.clone()).await;
+                        exchange_task(api, caches, db).await
+                    }.boxed_local()
+                }),
+            })
+        };
+        {
+            let db = db.clone();
+            scheduler.add(Job{
+                name: "recurring",
+                every: Duration::from_secs(RECURRING_INTERVAL_SECS),
+                // Catch up on the periods that started while we were down right away
+                first_run: Duration::from_secs(0),
+                timeout: Duration::from_secs(5 * 60),
+                concurrency: 1,
+                run: Box::new(move || {
+                    let db = db.clone();
+                    async move {
+                        recurring_task(client, db, issuer).await;
+                        Ok(())
+                    }.boxed_local()
+                }),
+            });
+        }
+        if !alerts_conf.rules.is_empty() {
+            let caches = caches.clone();
+            let db = db.clone();
+            scheduler.add(Job{
+                name: "alerts",
+                every: Duration::from_secs(ALERTS_INTERVAL_SECS),
+                first_run: Duration::from_secs(ALERTS_INTERVAL_SECS),
+                timeout: Duration::from_secs(2 * 60),
+                concurrency: 1,
+                run: Box::new(move || {
+                    alerts_task(client, caches.clone(), db.clone(), alerter, channels).boxed_local()
+                }),
+            });
+        }
+
+        runtime.block_on(async {
+            scheduler.run_now(update_rates).await;
+            let init = scheduler.run_now(exchange).await;
 
-        let mut cron = Scheduler::new(api, caches_outer.clone());
-        let cron = &mut cron;
-
-        current_thread::block_on_all(futures::lazy(move || {
-            let fut_update_rates = update_rates_task(api, caches_outer.clone());
-            let fut_exchange = exchange_task(api);
-            let fut_init = fut_update_rates.then(|_| fut_exchange);
-
-            let ticker = Interval::new_interval(Duration::from_secs(1));
-            let fut_cron = ticker
-                .map_err(|_| ())
-                .for_each(move |_| cron.tick());
-
-            fut_init
-                // Unblock the main thread
-                .then(move |r| {
-                    tx.send(r.is_ok()).unwrap();
-                    r
-                })
-                // Continue with cron
-                .and_then(|_| fut_cron)
-        })).unwrap();
+            // Unblock the main thread, even when Coinmotion couldn't be reached
+            // the server starts up and quotes once the rates come through
+            if init != JobOutcome::Ok {
+                warn!("Starting up without fresh rates or balances");
+            }
+            tx.send(true).unwrap();
+
+            scheduler.run().await
+        });
     });
 
     rx.recv().unwrap_or(false)
@@ -62,116 +155,718 @@ pub fn start(cm_conf: CoinmotionConfig, caches: Arc<Caches>) -> bool {
 
 const UPDATE_RATES_INTERVAL_SECS: u64 = 60;
 const EXCHANGE_INTERVAL_SECS: u64 = 5 * 60;
+const RECURRING_INTERVAL_SECS: u64 = 10 * 60;
+const ALERTS_INTERVAL_SECS: u64 = 60;
+/// How long a submitted sell or withdrawal may take to show up in the Coinmotion history
+pub const RECONCILE_GRACE_MINS: i64 = 30;
+/// Margin for the clocks of Coinmotion and ours not agreeing, when looking through the history
+const RECONCILE_CLOCK_SKEW_SECS: i64 = 10 * 60;
+
+type JobFuture<'a> = LocalBoxFuture<'a, Result<(), ()>>;
+
+/// A periodic job of the worker. Jobs run independently of each other, a slow
+/// or failing job doesn't hold up the rest.
+struct Job<'a> {
+    name: &'static str,
+    every: Duration,
+    /// Delay from startup until the first scheduled run
+    first_run: Duration,
+    /// A run taking longer than this is abandoned and counts as failed
+    timeout: Duration,
+    /// Runs of the job that may be in progress at once, a run that comes due
+    /// while at the limit is skipped
+    concurrency: usize,
+    /// Errors have been logged by the job by the time it resolves
+    run: Box<dyn Fn() -> JobFuture<'a> + 'a>,
+}
+
+struct Scheduled<'a> {
+    job: Job<'a>,
+    next_run: Instant,
+    running: usize,
+}
+
+/// How the last run of a job ended
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobOutcome {
+    Ok,
+    Failed,
+    TimedOut,
+}
+
+/// What is known about the runs of a job, shown in the health report
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStats {
+    pub every_secs: u64,
+    /// Runs in progress right now
+    pub running: usize,
+    pub last_started: Option<DateTime<Utc>>,
+    pub last_duration_ms: Option<u64>,
+    pub last_outcome: Option<JobOutcome>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub runs: u64,
+    pub failures: u64,
+    /// Runs that came due while the job was at its concurrency limit
+    pub skipped: u64,
+}
+
+impl JobStats {
+    fn new(every: Duration) -> Self {
+        Self{
+            every_secs: every.as_secs(),
+            running: 0,
+            last_started: None,
+            last_duration_ms: None,
+            last_outcome: None,
+            last_success: None,
+            runs: 0,
+            failures: 0,
+            skipped: 0,
+        }
+    }
+
+    fn started(&mut self) {
+        self.running += 1;
+        self.runs += 1;
+        self.last_started = Some(Utc::now());
+    }
+
+    fn finished(&mut self, outcome: JobOutcome, duration: Duration) {
+        self.running -= 1;
+        self.last_duration_ms = Some(duration.as_millis() as u64);
+        self.last_outcome = Some(outcome);
+        if outcome == JobOutcome::Ok {
+            self.last_success = Some(Utc::now());
+        } else {
+            self.failures += 1;
+        }
+    }
+}
 
 struct Scheduler<'a> {
-    api: &'a Coinmotion<'a>,
+    jobs: Vec<Scheduled<'a>>,
     caches: Arc<Caches>,
-    update_rates_time: SystemTime,
-    exchange_time: SystemTime,
 }
 
 impl<'a> Scheduler<'a> {
-    fn new(api: &'a Coinmotion<'a>, caches: Arc<Caches>) -> Self {
-        let now = SystemTime::now();
+    fn new(caches: Arc<Caches>) -> Self {
         Self{
-            api,
+            jobs: vec![],
             caches,
-            update_rates_time: now + Duration::from_secs(UPDATE_RATES_INTERVAL_SECS),
-            exchange_time: now + Duration::from_secs(EXCHANGE_INTERVAL_SECS),
         }
     }
 
-    fn tick(&mut self) -> impl Future<Item=(), Error=()> + 'a {
-        let now = SystemTime::now();
+    /// Schedule the job, the returned index can be passed to `run_now`
+    fn add(&mut self, job: Job<'a>) -> usize {
+        self.caches.jobs().write().unwrap()
+            .insert(job.name, JobStats::new(job.every));
+        self.jobs.push(Scheduled{
+            next_run: Instant::now() + job.first_run,
+            running: 0,
+            job,
+        });
+        self.jobs.len() - 1
+    }
 
-        let run_update_rates = self.update_rates_time <= now;
-        let run_exchange = self.exchange_time <= now;
+    /// Run a job outside of its schedule and wait for it to finish
+    async fn run_now(&mut self, index: usize) -> JobOutcome {
+        let (_, outcome) = self.start(index).await;
+        self.jobs[index].running -= 1;
+        outcome
+    }
+
+    /// Start the jobs as they come due, for as long as the worker runs
+    async fn run(&mut self) {
+        let mut in_progress = FuturesUnordered::new();
+        let mut ticker = time::interval(Duration::from_secs(1));
 
-        if run_update_rates {
-            self.update_rates_time = now + Duration::from_secs(UPDATE_RATES_INTERVAL_SECS);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    *self.caches.worker_heartbeat().write().unwrap() = Some(SystemTime::now());
+                    let now = Instant::now();
+                    for index in 0..self.jobs.len() {
+                        let scheduled = &mut self.jobs[index];
+                        if scheduled.next_run > now {
+                            continue;
+                        }
+                        scheduled.next_run = now + scheduled.job.every;
+                        if scheduled.running >= scheduled.job.concurrency {
+                            debug!("Skipping job {}, it is still running", scheduled.job.name);
+                            if let Some(stats) = self.caches.jobs().write().unwrap().get_mut(scheduled.job.name) {
+                                stats.skipped += 1;
+                            }
+                            continue;
+                        }
+                        in_progress.push(self.start(index));
+                    }
+                },
+                Some((index, _)) = in_progress.next() => {
+                    self.jobs[index].running -= 1;
+                },
+            }
         }
-        if run_exchange {
-            self.exchange_time = now + Duration::from_secs(EXCHANGE_INTERVAL_SECS);
+    }
+
+    fn start(&mut self, index: usize) -> LocalBoxFuture<'a, (usize, JobOutcome)> {
+        let scheduled = &mut self.jobs[index];
+        scheduled.running += 1;
+
+        let name = scheduled.job.name;
+        let timeout = scheduled.job.timeout;
+        let run = (scheduled.job.run)();
+        let caches = self.caches.clone();
+        logging::in_span(logging::new_span_id(name), async move {
+            trace!(job = name; "Starting job {}", name);
+            if let Some(stats) = caches.jobs().write().unwrap().get_mut(name) {
+                stats.started();
+            }
+
+            let started = Instant::now();
+            let outcome = match time::timeout(timeout, run).await {
+                Ok(Ok(())) => JobOutcome::Ok,
+                Ok(Err(())) => JobOutcome::Failed,
+                Err(_) => {
+                    error!(job = name; "Job {} timed out after {}s", name, timeout.as_secs());
+                    JobOutcome::TimedOut
+                },
+            };
+
+            let duration = started.elapsed();
+            debug!(job = name, outcome:? = outcome, duration_ms = duration.as_millis() as u64; "Finished job {}", name);
+            if let Some(stats) = caches.jobs().write().unwrap().get_mut(name) {
+                stats.finished(outcome, duration);
+            }
+            (index, outcome)
+        }).boxed_local()
+    }
+}
+
+async fn update_rates_task(api: &Coinmotion<'_>, client: &Client, rates_conf: &RatesConfig, caches: Arc<Caches>, history: Arc<RateHistory>) -> Result<(), ()> {
+    // Failing sources are left out rather than failing the whole update
+    let fut_primary = async {
+        api.rates().await
+            .map_err(|err| log_api_error("Fetching Coinmotion rates", &err))
+            .ok()
+    };
+    let fut_secondary = future::join_all(rates_conf.sources.iter()
+        .map(|source| async move {
+            match rates::fetch(client, source).await {
+                Ok(rates) => Some((source.name.as_str(), rates)),
+                Err(err) => {
+                    warn!(source = source.name.as_str(); "Failed to fetch rates from {}: {}", source.name, err);
+                    None
+                },
+            }
+        }));
+
+    let (primary, secondary) = future::join(fut_primary, fut_secondary).await;
+    let secondary: Vec<_> = secondary.into_iter().flatten().collect();
+    match rates::reconcile(primary, &secondary, &rates_conf.max_deviation_percent) {
+        Ok((rates, source)) => {
+            if source != "coinmotion" {
+                warn!(source = source; "Falling back to {} rates", source);
+            }
+            trace!(btc_bid:% = rates.btc_bid, btc_ask:% = rates.btc_ask; "Updating cached rates - BTC bid: {} - BTC ask: {}", rates.btc_bid, rates.btc_ask);
+            if let Err(err) = history.record(rates.btc_bid.clone(), rates.btc_ask.clone()) {
+                error!("Failed to record rate history: {}", err);
+            }
+            caches.rates().write().unwrap().set(rates);
+            *caches.rates_trusted().write().unwrap() = true;
+            Ok(())
+        },
+        // Nothing to compare, the last trusted rates stay in use until they expire
+        Err(rates::Error::NoSources) => {
+            warn!("No rates could be fetched, keeping the cached rates");
+            Err(())
+        },
+        Err(rates::Error::Disagreement(disagreement)) => {
+            error!(
+                source = disagreement.source.as_str(), deviation_percent:% = disagreement.deviation_percent;
+                "Refusing to quote, rates are not trustworthy: {}% off from {} at best", disagreement.deviation_percent, disagreement.source
+            );
+            *caches.rates_trusted().write().unwrap() = false;
+            *caches.rate_disagreement().write().unwrap() = Some(disagreement);
+            Err(())
+        },
+        Err(err) => {
+            error!("Refusing to quote, rates are not trustworthy: {}", err);
+            *caches.rates_trusted().write().unwrap() = false;
+            Err(())
+        },
+    }
+}
+
+async fn refunds_task(api: &Coinmotion<'_>, caches: Arc<Caches>, db: Arc<Database>) {
+    // The refunds are recorded in the ledger at the current rate, so wait until there is one
+    let btc_ask = caches.rates().read().unwrap().try_get().map(|r| r.btc_ask);
+    let btc_ask = match btc_ask {
+        Some(btc_ask) => btc_ask,
+        None => return,
+    };
+    let approved: Vec<Refund> = db.refunds().into_iter()
+        .filter(|r| r.status == RefundStatus::Approved)
+        .collect();
+
+    for refund in approved {
+        let one = BigDecimal::one().into_bigint_and_exponent().0;
+        let mul = BigDecimal::new(one, -8);
+        let satoshis = (refund.btc_amount.clone() * mul).with_scale(0)
+            .to_u64().unwrap();
+
+        // Mark the refund as being sent up front, so that a send with an unknown
+        // outcome is never retried without a human looking at it first
+        if let Err(err) = db.update_refund(refund.id, RefundStatus::Approved, RefundStatus::Sending) {
+            error!(refund_id = refund.id, charge_id = refund.charge_id; "Failed to update refund {}: {:?}", refund.id, err);
+            continue;
         }
 
-        let fut_tasks = if run_update_rates {
-            let fut = update_rates_task(self.api, self.caches.clone());
-            box_task(fut)
-        } else {
-            box_task(noop_task())
+        info!(refund_id = refund.id, charge_id = refund.charge_id, address = refund.address.as_str(); "Sending refund {} of {} BTC to {}", refund.id, refund.btc_amount, refund.address);
+        match api.send_btc(&refund.address, satoshis).await {
+            Ok(sent) => {
+                let id = sent.id;
+                let reference = sent.txid.unwrap_or_else(|| id.to_string());
+                if let Err(err) = db.complete_refund(refund.id, &reference, btc_ask.clone()) {
+                    error!(refund_id = refund.id, reference = reference.as_str(); "Failed to record sent refund {}: {:?}", refund.id, err);
+                }
+            },
+            Err(err) => {
+                log_api_error("Sending refund", &err);
+                let status = if err.outcome_unknown() {
+                    RefundStatus::Sending
+                } else {
+                    RefundStatus::Failed
+                };
+                if let Err(err) = db.fail_refund(refund.id, status, &err.to_string()) {
+                    error!(refund_id = refund.id; "Failed to record the failure of refund {}: {:?}", refund.id, err);
+                }
+            },
+        }
+    }
+}
+
+async fn recurring_task(client: &Client, db: Arc<Database>, issuer: &Issuer) {
+    let today = Utc::now().naive_utc().date();
+    for template in issuer.templates.iter() {
+        let charge = match template.charge_for(today) {
+            Some(charge) => charge,
+            None => continue,
         };
+        match db.issue_charge(charge, &template.btc_addresses) {
+            Ok(Some(charge)) => {
+                let url = web::charge_url(&issuer.hashids, &issuer.base_url, charge.id);
+                info!(
+                    charge_id = charge.id, invoice_id = charge.invoice_id.as_str(), template = template.id.as_str();
+                    "Issued {} ({} EUR) for {} at {}", charge.invoice_id, charge.eur_amount, template.client, url
+                );
+            },
+            Ok(None) => {},
+            Err(err) => error!(template = template.id.as_str(); "Failed to issue recurring charge {}: {:?}", template.id, err),
+        }
+    }
 
-        let fut_tasks = if run_exchange {
-            let fut = exchange_task(self.api);
-            box_task(fut_tasks.then(|_| fut))
-        } else {
-            fut_tasks
+    // Notifications are retried on every run until the webhook or sendmail accepts them
+    for charge in db.charges() {
+        let recurrence = match charge.recurrence {
+            Some(ref r) if !r.notified || !r.emailed => r,
+            _ => continue,
+        };
+        let template = match issuer.templates.iter().find(|t| t.id == recurrence.template) {
+            Some(template) => template,
+            None => continue,
         };
+        let url = web::charge_url(&issuer.hashids, &issuer.base_url, charge.id).into_string();
+        let notification = ChargeNotification::new(template, &charge, &url);
 
-        // Map any error from the tasks to a successful noop
-        fut_tasks.or_else(|_| noop_task())
-    }
-}
-
-fn box_task<'a, F>(fut: F) -> Box<Future<Item=(), Error=()> + 'a>
-    where F: Future<Item=(), Error=()> + 'a
-{
-    Box::new(fut)
-}
-
-fn noop_task() -> impl Future<Item=(), Error=()> {
-    futures::future::ok(())
-}
-
-fn update_rates_task(api: &Coinmotion, caches: Arc<Caches>) -> impl Future<Item=(), Error=()> {
-    api.rates()
-        .map(move |rates| {
-            let mut rw_rates = caches.rates().write().unwrap();
-            trace!("Updating cached rates - BTC bid: {} - BTC ask: {}", rates.btc_bid, rates.btc_ask);
-            rw_rates.set(rates);
-        })
-        .map_err(|err| {
-            error!("Failed to update rates cache: {:?}", err);
-        })
-}
-
-fn exchange_task<'a>(api: &'a Coinmotion) -> impl Future<Item=(), Error=()> + 'a {
-    let fut_balances = api.balances();
-
-    fut_balances
-        .map_err(|err| {
-            error!("Failed to fetch balances: {:?}", err);
-        })
-        .and_then(move |bal| -> Box<Future<Item=(), Error=()> + 'a> {
-            let one = BigDecimal::one().into_bigint_and_exponent().0;
-            let withdrawal_fee = BigDecimal::from_str(WITHDRAWAL_FEE).unwrap();
-
-            if !bal.btc_avl.is_zero() {
-                // Always try to exchange BTC to EUR as quickly as possible
-                let mul = BigDecimal::new(one, -8);
-                let satoshis = (bal.btc_avl * mul).with_scale(0)
-                    .to_u64().unwrap();
-                Box::new(api.sell(BuySellAmount::BtcSatoshis(satoshis))
-                    .map_err(|err| {
-                        error!("Failed to sell BTC: {:?}", err);
-                    })
-                    .map(|_| ()))
-
-            } else if bal.eur_avl > withdrawal_fee {
-                // As a 2nd priority try to withdraw any balance we have
-                let amount = bal.eur_avl - withdrawal_fee;
-                let mul = BigDecimal::new(one, -2);
-                let cents = (amount * mul).with_scale(0)
-                    .to_u64().unwrap();
-                Box::new(api.withdraw(cents)
-                    .map_err(|err| {
-                        error!("Failed to request withdrawal: {:?}", err);
-                    })
-                    .map(|_| ()))
-
-            } else {
-                // And there's nothing else to do
-                Box::new(futures::future::ok(()))
+        if let (false, Some(webhook_url)) = (recurrence.notified, template.webhook_url.as_ref()) {
+            if post_notification(client, webhook_url, &notification, &charge).await {
+                if let Err(err) = db.mark_notified(charge.id) {
+                    error!(charge_id = charge.id; "Failed to mark {} as notified: {:?}", charge.invoice_id, err);
+                }
             }
-        })
+        }
+
+        let email = issuer.mail.as_ref()
+            .and_then(|mail| Some((mail, notification.email(&mail.from)?)));
+        if let (false, Some((mail, email))) = (recurrence.emailed, email) {
+            match alerts::sendmail_message(&mail.sendmail, email).await {
+                Ok(()) => if let Err(err) = db.mark_emailed(charge.id) {
+                    error!(charge_id = charge.id; "Failed to mark {} as emailed: {:?}", charge.invoice_id, err);
+                },
+                Err(err) => error!(charge_id = charge.id; "Emailing {} failed: {}", charge.invoice_id, err),
+            }
+        }
+    }
+}
+
+async fn post_notification(client: &Client, webhook_url: &str, notification: &ChargeNotification<'_>, charge: &Charge) -> bool {
+    let uri = match webhook_url.parse::<hyper::Uri>() {
+        Ok(uri) => uri,
+        Err(err) => {
+            error!(template = notification.template; "Invalid webhook_url for recurring charge {}: {}", notification.template, err);
+            return false;
+        },
+    };
+
+    let mut req = Request::new(serde_json::to_string(notification).unwrap().into());
+    *req.method_mut() = Method::POST;
+    *req.uri_mut() = uri;
+    req.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(mime::APPLICATION_JSON.as_ref()).unwrap(),
+    );
+
+    match client.request(req).await {
+        Ok(ref res) if res.status().is_success() => true,
+        Ok(res) => {
+            error!(charge_id = charge.id, status = res.status().as_u16(); "Webhook for {} failed with {}", charge.invoice_id, res.status());
+            false
+        },
+        Err(err) => {
+            error!(charge_id = charge.id; "Webhook for {} failed: {:?}", charge.invoice_id, err);
+            false
+        },
+    }
+}
+
+async fn alerts_task(client: &Client, caches: Arc<Caches>, db: Arc<Database>, alerter: &RefCell<Alerter>, channels: &[Channel]) -> Result<(), ()> {
+    let btc_avl = caches.balances().read().unwrap().as_ref()
+        .map(|bal| bal.btc_avl.clone())
+        .unwrap_or_else(BigDecimal::zero);
+    let observed = Observed{
+        now: Utc::now(),
+        rates_age: caches.rates().read().unwrap().age()
+            .and_then(|age| chrono::Duration::from_std(age).ok()),
+        sell_failures: *caches.sell_failures().read().unwrap(),
+        btc_sellable: btc_avl - db.reserved_btc(),
+        sell_below_minimum: *caches.sell_below_minimum().read().unwrap(),
+        last_sale: db.sales().last().map(|sale| sale.sold_at),
+        pending_withdrawal: db.unfinished_exchange()
+            .filter(|action| action.kind == ExchangeKind::Withdrawal),
+    };
+    let fired = alerter.borrow_mut().check(&observed);
+
+    let mut delivered = true;
+    for alert in fired {
+        warn!(rule = alert.rule, status:? = alert.status; "Alert: {}", alert.summary);
+        // Without channels the alert is only logged
+        let mut accepted = channels.is_empty();
+        for channel in channels {
+            match alerts::deliver(client, channel, &alert).await {
+                Ok(()) => accepted = true,
+                Err(err) => {
+                    error!(rule = alert.rule; "Failed to deliver alert to {:?}: {}", channel, err);
+                    delivered = false;
+                },
+            }
+        }
+        if !accepted {
+            alerter.borrow_mut().undelivered(&alert);
+        }
+    }
+    if delivered { Ok(()) } else { Err(()) }
+}
+
+async fn exchange_task(api: &Coinmotion<'_>, caches: Arc<Caches>, db: Arc<Database>) -> Result<(), ()> {
+    let bal = api.balances().await
+        .map_err(|err| log_api_error("Fetching balances", &err))?;
+    *caches.balances().write().unwrap() = Some(bal.clone());
+
+    // Find out how the last sell or withdrawal ended before making another one
+    if let Some(action) = db.unfinished_exchange() {
+        return reconcile_exchange(api, &caches, &db, action, &bal).await;
+    }
+
+    let one = BigDecimal::one().into_bigint_and_exponent().0;
+    let withdrawal_fee = BigDecimal::from_str(WITHDRAWAL_FEE).unwrap();
+
+    // Keep hold of the BTC that is owed back to payers
+    let btc_sellable = bal.btc_avl.clone() - db.reserved_btc();
+
+    if btc_sellable > BigDecimal::zero() {
+        // Always try to exchange BTC to EUR as quickly as possible
+        let mul = BigDecimal::new(one, -8);
+        let satoshis = (btc_sellable * mul).with_scale(0)
+            .to_u64().unwrap();
+        let action = submit_exchange(&db, ExchangeKind::Sell, satoshis, bal.btc_avl)?;
+        match api.sell(BuySellAmount::BtcSatoshis(satoshis)).await {
+            Ok(trade) => {
+                *caches.sell_failures().write().unwrap() = 0;
+                *caches.sell_below_minimum().write().unwrap() = false;
+                confirm_sale(&db, action.id, trade)
+            },
+            Err(err) => {
+                let below_minimum = matches!(err, coinmotion::Error::BelowMinimum(..));
+                *caches.sell_below_minimum().write().unwrap() = below_minimum;
+                // Not a failure, the balance is still building up
+                if !below_minimum {
+                    *caches.sell_failures().write().unwrap() += 1;
+                }
+                exchange_failed(&db, action.id, "Selling BTC", err)
+            },
+        }
+
+    } else if bal.eur_avl > withdrawal_fee {
+        // As a 2nd priority try to withdraw any balance we have
+        let amount = bal.eur_avl.clone() - withdrawal_fee;
+        let mul = BigDecimal::new(one, -2);
+        let cents = (amount * mul).with_scale(0)
+            .to_u64().unwrap();
+        let action = submit_exchange(&db, ExchangeKind::Withdrawal, cents, bal.eur_avl)?;
+        match api.withdraw(cents).await {
+            Ok(withdrawal) => {
+                info!(
+                    exchange_id = action.id, ref_no = withdrawal.ref_no.as_str();
+                    "Withdrew {} EUR with reference {}", BigDecimal::new(cents.into(), 2), withdrawal.ref_no
+                );
+                db.confirm_exchange(action.id, Some(&withdrawal.ref_no), None)
+                    .map_err(|err| error!(
+                        exchange_id = action.id, ref_no = withdrawal.ref_no.as_str();
+                        "Failed to record withdrawal {}: {:?}", action.id, err
+                    ))
+            },
+            Err(err) => exchange_failed(&db, action.id, "Requesting withdrawal", err),
+        }
+
+    } else {
+        Ok(())
+    }
+}
+
+/// Record the action as submitted, ahead of the call to Coinmotion
+fn submit_exchange(db: &Database, kind: ExchangeKind, amount: u64, balance_before: BigDecimal) -> Result<ExchangeAction, ()> {
+    db.record_exchange(kind, amount, balance_before)
+        .map_err(|err| error!(kind:? = kind; "Failed to record {:?}: {:?}", kind, err))
+}
+
+fn confirm_sale(db: &Database, action_id: u64, trade: Trade) -> Result<(), ()> {
+    let trade_id = trade.id;
+    // Coinmotion reports the BTC going out of the account as negative
+    let sale = Sale{
+        trade_id: trade_id.clone(),
+        btc_amount: trade.amount_vir.abs(),
+        eur_amount: trade.amount_cur,
+        btc_rate: trade.rate,
+        sold_at: Utc::now(),
+    };
+    info!(exchange_id = action_id, trade_id = trade_id.as_str(); "Sold {} BTC at {} EUR", sale.btc_amount, sale.btc_rate);
+    db.confirm_exchange(action_id, Some(&trade_id), Some(sale))
+        .map_err(|err| error!(exchange_id = action_id, trade_id = trade_id.as_str(); "Failed to record sale {}: {:?}", trade_id, err))
+}
+
+fn exchange_failed(db: &Database, action_id: u64, action: &str, err: coinmotion::Error) -> Result<(), ()> {
+    log_api_error(action, &err);
+    if err.outcome_unknown() {
+        // Left as submitted, so that no other action is made until it has been reconciled
+        warn!(exchange_id = action_id; "{} may have gone through anyway, checking on the next run", action);
+        return Err(());
+    }
+
+    match err {
+        // Not a failure, the balance is still building up. Kept out of the history,
+        // as there would be one on every run until then.
+        coinmotion::Error::BelowMinimum(..) => db.discard_exchange(action_id)
+            .map_err(|err| error!(exchange_id = action_id; "Failed to discard exchange action {}: {:?}", action_id, err)),
+        // Coinmotion turned it down, kept with the reason for a human to look at
+        _ => {
+            if let Err(err) = db.fail_exchange(action_id, &err.to_string()) {
+                error!(exchange_id = action_id; "Failed to record exchange action {} as failed: {:?}", action_id, err);
+            }
+            Err(())
+        },
+    }
+}
+
+/// Settle an action that a crash or a lost response left unfinished, by looking for
+/// it in the Coinmotion history
+async fn reconcile_exchange(api: &Coinmotion<'_>, caches: &Caches, db: &Database, action: ExchangeAction, bal: &Balances) -> Result<(), ()> {
+    let page = HistoryPage{
+        since: Some(action.submitted_at.timestamp() - RECONCILE_CLOCK_SKEW_SECS),
+        ..HistoryPage::default()
+    };
+    let exchanges = db.exchanges();
+    match action.kind {
+        ExchangeKind::Sell => {
+            let trades = api.trades(page).await
+                .map_err(|err| log_api_error("Fetching trades", &err))?;
+            let sold: Vec<String> = db.sales().into_iter().map(|s| s.trade_id).collect();
+            if let Some(trade) = find_trade(trades, action.amount, &sold) {
+                info!(exchange_id = action.id, trade_id = trade.id.as_str(); "Sell {} went through as trade {}", action.id, trade.id);
+                *caches.sell_failures().write().unwrap() = 0;
+                return confirm_sale(db, action.id, trade);
+            }
+
+            // Not listed yet, but the BTC has left the account. Without the trade there
+            // is no sale to record, so it is given the grace period to show up first.
+            if bal.btc_avl.clone() + BigDecimal::new(action.amount.into(), 8) <= action.balance_before {
+                if !grace_period_over(&action) {
+                    debug!(exchange_id = action.id; "Sell {} has taken the BTC but isn't listed yet, checking again on the next run", action.id);
+                    return Ok(());
+                }
+                warn!(exchange_id = action.id; "Sell {} has taken the BTC but isn't listed as a trade, recording it without a sale", action.id);
+                return db.confirm_exchange(action.id, None, None)
+                    .map_err(|err| error!(exchange_id = action.id; "Failed to record sell {}: {:?}", action.id, err));
+            }
+        },
+        ExchangeKind::Withdrawal => {
+            let transactions = api.transactions(page).await
+                .map_err(|err| log_api_error("Fetching transactions", &err))?;
+            let known: Vec<String> = exchanges.iter().filter_map(|e| e.reference.clone()).collect();
+            let reference = match find_withdrawal(&transactions, action.amount, &known) {
+                Some(reference) => Some(reference),
+                // Not listed yet, but the EUR has left the account
+                None if bal.eur_avl.clone() + BigDecimal::new(action.amount.into(), 2) <= action.balance_before => None,
+                None => return give_up_on_exchange(db, &action),
+            };
+            info!(exchange_id = action.id, ref_no = reference.as_deref(); "Withdrawal {} went through", action.id);
+            return db.confirm_exchange(action.id, reference.as_deref(), None)
+                .map_err(|err| error!(exchange_id = action.id; "Failed to record withdrawal {}: {:?}", action.id, err));
+        },
+    }
+
+    give_up_on_exchange(db, &action)
+}
+
+fn grace_period_over(action: &ExchangeAction) -> bool {
+    Utc::now() - action.submitted_at >= chrono::Duration::minutes(RECONCILE_GRACE_MINS)
+}
+
+/// An action that isn't in the history is given some time to show up, before it
+/// is taken not to have gone through
+fn give_up_on_exchange(db: &Database, action: &ExchangeAction) -> Result<(), ()> {
+    if !grace_period_over(action) {
+        debug!(exchange_id = action.id; "{:?} {} isn't on Coinmotion yet, checking again on the next run", action.kind, action.id);
+        return Ok(());
+    }
+
+    warn!(exchange_id = action.id; "{:?} {} isn't on Coinmotion, taking it not to have gone through", action.kind, action.id);
+    db.fail_exchange(action.id, "Not found in the Coinmotion history")
+        .map_err(|err| error!(exchange_id = action.id; "Failed to record exchange action {} as failed: {:?}", action.id, err))
+}
+
+/// The trade that sold exactly the given amount and hasn't been recorded as a sale yet
+fn find_trade(trades: Vec<Trade>, satoshis: u64, sold: &[String]) -> Option<Trade> {
+    let btc_amount = BigDecimal::new(satoshis.into(), 8);
+    trades.into_iter()
+        .find(|t| t.amount_vir.abs() == btc_amount && !sold.contains(&t.id))
+}
+
+/// Reference of the EUR withdrawal of exactly the given amount, that isn't already
+/// known to belong to another action
+fn find_withdrawal(transactions: &[Transaction], cents: u64, known: &[String]) -> Option<String> {
+    let eur_amount = BigDecimal::new(cents.into(), 2);
+    transactions.iter()
+        .filter(|t| t.kind == "withdrawal" && t.currency == "EUR" && t.amount.abs() == eur_amount)
+        .map(|t| t.reference.clone().unwrap_or_else(|| t.id.clone()))
+        .find(|reference| !known.contains(reference))
+}
+
+fn log_api_error(action: &str, err: &coinmotion::Error) {
+    let error = err.name();
+    match *err {
+        // Expected while the balance builds up
+        coinmotion::Error::BelowMinimum(..) => debug!(error; "{} skipped: {}", action, err),
+        coinmotion::Error::InvalidCredentials(_) =>
+            error!(error; "{} failed, check api_key and api_secret in [coinmotion]: {}", action, err),
+        coinmotion::Error::Maintenance(_) |
+        coinmotion::Error::RateLimited{..} |
+        coinmotion::Error::CircuitOpen => warn!(error; "{} postponed: {}", action, err),
+        _ => error!(error; "{} failed: {}", action, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job<'a>(name: &'static str, timeout: Duration, run: Box<dyn Fn() -> JobFuture<'a> + 'a>) -> Job<'a> {
+        Job{
+            name,
+            every: Duration::from_secs(0),
+            first_run: Duration::from_secs(0),
+            timeout,
+            concurrency: 1,
+            run,
+        }
+    }
+
+    fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+        runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(fut)
+    }
+
+    #[test]
+    fn job_runs_are_recorded() {
+        let caches = Arc::new(Caches::new());
+        let mut scheduler = Scheduler::new(caches.clone());
+        let ok = scheduler.add(job("ok", Duration::from_secs(1), Box::new(|| future::ok(()).boxed_local())));
+        let failing = scheduler.add(job("failing", Duration::from_secs(1), Box::new(|| future::err(()).boxed_local())));
+        let slow = scheduler.add(job("slow", Duration::from_millis(10), Box::new(|| async {
+            time::sleep(Duration::from_secs(1)).await;
+            Ok(())
+        }.boxed_local())));
+
+        block_on(async {
+            assert_eq!(scheduler.run_now(ok).await, JobOutcome::Ok);
+            assert_eq!(scheduler.run_now(failing).await, JobOutcome::Failed);
+            assert_eq!(scheduler.run_now(slow).await, JobOutcome::TimedOut);
+        });
+
+        let jobs = caches.jobs().read().unwrap();
+        assert_eq!(jobs["ok"].runs, 1);
+        assert_eq!(jobs["ok"].failures, 0);
+        assert!(jobs["ok"].last_success.is_some());
+        assert_eq!(jobs["failing"].last_outcome, Some(JobOutcome::Failed));
+        assert_eq!(jobs["slow"].failures, 1);
+        assert_eq!(jobs["slow"].running, 0);
+    }
+
+    #[test]
+    fn unfinished_exchanges_are_found_in_history() {
+        let trades: Vec<Trade> = serde_json::from_str(r#"[
+            {"id": "360002", "rate": "40000", "timestamp": "2018-07-06 21:09:54", "amount_cur": 500, "amount_vir": -0.0125},
+            {"id": "360001", "rate": "40000", "timestamp": "2018-07-06 21:04:54", "amount_cur": 500, "amount_vir": -0.0125}
+        ]"#).unwrap();
+        assert_eq!(find_trade(trades.clone(), 1250000, &[]).unwrap().id, "360002");
+        assert_eq!(find_trade(trades.clone(), 1250000, &["360002".to_string()]).unwrap().id, "360001");
+        assert!(find_trade(trades, 1250001, &[]).is_none());
+
+        let transactions: Vec<Transaction> = serde_json::from_str(r#"[
+            {"id": 50003, "type": "fee", "currency": "EUR", "amount": "-0.90", "balance": "0", "timestamp": "2018-07-06 21:15:00"},
+            {"id": 50002, "type": "withdrawal", "currency": "EUR", "amount": "-499.10", "balance": "0.90", "timestamp": "2018-07-06 21:15:00", "reference": "X123"},
+            {"id": 50001, "type": "withdrawal", "currency": "EUR", "amount": "-499.10", "balance": "500", "timestamp": "2018-07-05 21:15:00"}
+        ]"#).unwrap();
+        assert_eq!(find_withdrawal(&transactions, 49910, &[]), Some("X123".to_string()));
+        assert_eq!(find_withdrawal(&transactions, 49910, &["X123".to_string()]), Some("50001".to_string()));
+        assert_eq!(find_withdrawal(&transactions, 90, &[]), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn busy_jobs_are_skipped() {
+        let caches = Arc::new(Caches::new());
+        let mut scheduler = Scheduler::new(caches.clone());
+        scheduler.add(job("busy", Duration::from_secs(10), Box::new(|| async {
+            time::sleep(Duration::from_secs(10)).await;
+            Ok(())
+        }.boxed_local())));
+        scheduler.add(job("quick", Duration::from_secs(10), Box::new(|| future::ok(()).boxed_local())));
+
+        // Ticks at 0s and 1s
+        tokio::select! {
+            biased;
+            _ = scheduler.run() => unreachable!(),
+            _ = time::advance(Duration::from_millis(1500)) => {},
+        }
+
+        let jobs = caches.jobs().read().unwrap();
+        assert_eq!(jobs["busy"].runs, 1);
+        assert_eq!(jobs["busy"].skipped, 1);
+        assert_eq!(jobs["busy"].running, 1);
+        assert_eq!(jobs["quick"].runs, 2);
+        assert_eq!(jobs["quick"].skipped, 0);
+    }
 }